@@ -0,0 +1,48 @@
+//! Per-request overhead of a fresh `LlamaLocalClient` per call, as the free
+//! functions used to do, against one long-lived client.
+//!
+//! Runs against a mock OpenAI-compatible server on localhost, so the numbers
+//! measure client setup and connection handling rather than generation.
+//!
+//!     cargo bench --bench llm_client --features test-util
+
+use autogen_rust::llm_config::{ApiKeySource, LlmConfig};
+use autogen_rust::llm_llama_local::{LlamaLocalClient, LlmClient};
+use autogen_rust::test_server::{completion, user_message, MockResponse, MockServer};
+use std::time::{Duration, Instant};
+
+const REQUESTS: u32 = 500;
+
+async fn time_requests<F>(llm_config: &LlmConfig, mut client_for_request: F) -> Duration
+where
+    F: FnMut() -> LlamaLocalClient,
+{
+    let messages = vec![user_message("ping")];
+    let start = Instant::now();
+    for _ in 0..REQUESTS {
+        client_for_request()
+            .chat(messages.clone(), llm_config)
+            .await
+            .expect("mock server replies");
+    }
+    start.elapsed() / REQUESTS
+}
+
+#[tokio::main]
+async fn main() {
+    let server = MockServer::start(|_| MockResponse::json(200, completion("ok"))).await;
+
+    let llm_config = LlmConfig {
+        api_base: format!("{}/v1", server.url),
+        api_key: ApiKeySource::None,
+        ..Default::default()
+    };
+
+    let fresh = time_requests(&llm_config, LlamaLocalClient::new).await;
+    let shared = LlamaLocalClient::new();
+    let reused = time_requests(&llm_config, || shared.clone()).await;
+
+    println!("{} requests each", REQUESTS);
+    println!("new client per request: {:?}/request", fresh);
+    println!("shared client:          {:?}/request", reused);
+}
@@ -0,0 +1,321 @@
+use crate::conversable_agent::Message;
+use crate::llama_structs::{Content, LlamaResponseMessage};
+use crate::llm_config::LlmConfig;
+use crate::llm_error::LlmError;
+use crate::llm_llama_local::{request_fingerprint, LlmClient};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Format version written with every entry. Version 1 entries, written
+/// before tool calls had ids, have no `version` field.
+pub const CASSETTE_VERSION: u32 = 2;
+
+fn legacy_cassette_version() -> u32 {
+    1
+}
+
+/// One recorded LLM call, stored as a line of the cassette file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CassetteEntry {
+    #[serde(default = "legacy_cassette_version")]
+    pub version: u32,
+    pub model: String,
+    pub messages: Vec<Message>,
+    pub response: LlamaResponseMessage,
+}
+
+impl CassetteEntry {
+    fn matches(&self, model: &str, messages: &[Message]) -> bool {
+        self.model == model && replay_fingerprint(&self.messages) == replay_fingerprint(messages)
+    }
+
+    /// Version 1 entries store no call ids, so reading them generated random
+    /// ones. Replace those with ids derived from the entry's line, so every
+    /// replay hands out the same ids.
+    fn assign_legacy_call_ids(&mut self, line: usize) {
+        if let Content::ToolCalls { calls, .. } = &mut self.response.content {
+            for (i, call) in calls.iter_mut().enumerate() {
+                call.id = format!("call_cassette_{}_{}", line, i);
+            }
+        }
+    }
+}
+
+/// The request fingerprint without call ids. Ids are minted anew in every
+/// recording and are missing from version 1 entries, so requests are matched
+/// on everything else.
+fn replay_fingerprint(messages: &[Message]) -> serde_json::Value {
+    let mut messages = messages.to_vec();
+    for message in &mut messages {
+        match &mut message.content {
+            Some(Content::ToolCalls { calls, .. }) => {
+                calls.iter_mut().for_each(|call| call.id.clear());
+            }
+            Some(Content::ToolResult { call_id, .. }) => call_id.clear(),
+            _ => {}
+        }
+    }
+    request_fingerprint(&messages)
+}
+
+enum CassetteMode {
+    Record(Arc<dyn LlmClient>),
+    /// Recorded entries, `None` once an entry has been served.
+    Replay(Mutex<Vec<Option<CassetteEntry>>>),
+}
+
+/// Cassette-style backend for running conversations without a live server.
+///
+/// In record mode every call goes to the wrapped client and the request and
+/// reply are appended to a JSONL file. In replay mode replies are served from
+/// that file; a request with no recorded match fails with `LlmError::Replay`.
+pub struct CassetteClient {
+    path: PathBuf,
+    mode: CassetteMode,
+    writer: Mutex<()>,
+}
+
+impl CassetteClient {
+    pub fn record(path: impl AsRef<Path>, inner: Arc<dyn LlmClient>) -> Self {
+        CassetteClient {
+            path: path.as_ref().to_path_buf(),
+            mode: CassetteMode::Record(inner),
+            writer: Mutex::new(()),
+        }
+    }
+
+    pub fn replay(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let reader = BufReader::new(File::open(&path)?);
+        let mut entries = Vec::new();
+        for (n, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut entry: CassetteEntry = serde_json::from_str(&line).map_err(|e| {
+                anyhow::anyhow!("Bad cassette entry at {}:{}: {}", path.display(), n + 1, e)
+            })?;
+            if entry.version > CASSETTE_VERSION {
+                anyhow::bail!(
+                    "{}:{} has cassette version {}, this build reads up to {}",
+                    path.display(),
+                    n + 1,
+                    entry.version,
+                    CASSETTE_VERSION
+                );
+            }
+            if entry.version < 2 {
+                entry.assign_legacy_call_ids(n + 1);
+            }
+            entries.push(Some(entry));
+        }
+
+        Ok(CassetteClient {
+            path,
+            mode: CassetteMode::Replay(Mutex::new(entries)),
+            writer: Mutex::new(()),
+        })
+    }
+
+    fn append(&self, entry: &CassetteEntry) -> Result<(), LlmError> {
+        let line = serde_json::to_string(entry).map_err(|e| LlmError::Parse(e.to_string()))?;
+        let _guard = self.writer.lock().unwrap();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| LlmError::Config(format!("{}: {}", self.path.display(), e)))?;
+        writeln!(file, "{}", line)
+            .map_err(|e| LlmError::Config(format!("{}: {}", self.path.display(), e)))
+    }
+}
+
+#[async_trait]
+impl LlmClient for CassetteClient {
+    async fn chat(
+        &self,
+        messages: Vec<Message>,
+        llm_config: &LlmConfig,
+    ) -> Result<LlamaResponseMessage, LlmError> {
+        match &self.mode {
+            CassetteMode::Record(inner) => {
+                let response = inner.chat(messages.clone(), llm_config).await?;
+                self.append(&CassetteEntry {
+                    version: CASSETTE_VERSION,
+                    model: llm_config.model.clone(),
+                    messages,
+                    response: response.clone(),
+                })?;
+                Ok(response)
+            }
+            CassetteMode::Replay(entries) => {
+                let mut entries = entries.lock().unwrap();
+                let hit = entries.iter_mut().find(|slot| {
+                    slot.as_ref()
+                        .is_some_and(|entry| entry.matches(&llm_config.model, &messages))
+                });
+                match hit.and_then(|slot| slot.take()) {
+                    Some(entry) => Ok(LlamaResponseMessage {
+                        cached: true,
+                        ..entry.response
+                    }),
+                    None => Err(LlmError::Replay(format!(
+                        "no unused entry in {} matches model {} with request {}",
+                        self.path.display(),
+                        llm_config.model,
+                        replay_fingerprint(&messages)
+                    ))),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversable_agent::ConversableAgent;
+    use crate::llama_structs::parse_llama_content;
+    use crate::test_server::user_message;
+    use async_openai::types::{CompletionUsage, Role};
+
+    /// Answers every request with the same Hermes tool call, so each reply
+    /// carries a freshly generated call id.
+    struct ToolCallingClient;
+
+    #[async_trait]
+    impl LlmClient for ToolCallingClient {
+        async fn chat(
+            &self,
+            _messages: Vec<Message>,
+            _llm_config: &LlmConfig,
+        ) -> Result<LlamaResponseMessage, LlmError> {
+            Ok(LlamaResponseMessage::new(
+                parse_llama_content(
+                    r#"<tool_call>{"name": "get_time", "arguments": {"zone": "UTC"}}</tool_call>"#,
+                ),
+                Role::Assistant,
+                CompletionUsage {
+                    prompt_tokens: 10,
+                    completion_tokens: 5,
+                    total_tokens: 15,
+                },
+            ))
+        }
+    }
+
+    #[tokio::test]
+    async fn replay_serves_the_recorded_replies() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("calls.jsonl");
+        let config = LlmConfig::default();
+        let question = vec![user_message("what time is it?")];
+
+        let recorder = CassetteClient::record(&path, Arc::new(ToolCallingClient));
+        let recorded = recorder.chat(question.clone(), &config).await.unwrap();
+
+        let player = CassetteClient::replay(&path).unwrap();
+        let replayed = player.chat(question.clone(), &config).await.unwrap();
+        assert!(replayed.cached);
+        assert_eq!(
+            LlamaResponseMessage {
+                cached: false,
+                ..replayed
+            },
+            recorded
+        );
+
+        // every entry is served once
+        let err = player.chat(question, &config).await.unwrap_err();
+        assert!(matches!(err, LlmError::Replay(_)));
+    }
+
+    #[tokio::test]
+    async fn version_1_entries_replay_with_stable_call_ids() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("v1.jsonl");
+        let entry = serde_json::json!({
+            "model": "gpt-3.5-turbo",
+            "messages": [{"content": {"Text": "what time is it?"}, "name": null, "role": "user", "context": null}],
+            "response": {
+                "content": {"ToolCall": {"name": "get_time", "arguments": {"zone": "UTC"}}},
+                "role": "assistant",
+                "usage": {"prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15}
+            }
+        });
+        std::fs::write(&path, format!("{}\n", entry)).unwrap();
+        let config = LlmConfig {
+            model: "gpt-3.5-turbo".to_string(),
+            ..Default::default()
+        };
+        let question = vec![user_message("what time is it?")];
+
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let player = CassetteClient::replay(&path).unwrap();
+            let reply = player.chat(question.clone(), &config).await.unwrap();
+            match reply.content {
+                Content::ToolCalls { text, calls } => {
+                    assert_eq!(text, None);
+                    assert_eq!(calls.len(), 1);
+                    assert_eq!(calls[0].str_argument("zone"), Some("UTC"));
+                    ids.push(calls[0].id.clone());
+                }
+                other => panic!("expected a tool call, got {:?}", other),
+            }
+        }
+        assert_eq!(ids[0], "call_cassette_1_0");
+        assert_eq!(ids[0], ids[1]);
+    }
+
+    #[test]
+    fn newer_cassettes_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("v99.jsonl");
+        let entry = serde_json::json!({
+            "version": CASSETTE_VERSION + 1,
+            "model": "gpt-3.5-turbo",
+            "messages": [],
+            "response": {
+                "content": {"Text": "hi"},
+                "role": "assistant",
+                "usage": {"prompt_tokens": 1, "completion_tokens": 1, "total_tokens": 2}
+            }
+        });
+        std::fs::write(&path, format!("{}\n", entry)).unwrap();
+        assert!(CassetteClient::replay(&path).is_err());
+    }
+
+    #[tokio::test]
+    async fn conversations_replay_past_the_first_turn() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("conversation.jsonl");
+
+        async fn two_turns(client: CassetteClient) -> Vec<Message> {
+            let mut agent = ConversableAgent::new("assistant");
+            agent.set_llm_client(Arc::new(client));
+            let mut history = vec![user_message("what time is it?")];
+            let first = agent.a_generate_reply(history.clone(), None).await.unwrap();
+            history.extend([first, user_message("and in Tokyo?")]);
+            let second = agent.a_generate_reply(history.clone(), None).await.unwrap();
+            history.push(second);
+            history
+        }
+
+        let recorded = two_turns(CassetteClient::record(&path, Arc::new(ToolCallingClient))).await;
+        let replayed = two_turns(CassetteClient::replay(&path).unwrap()).await;
+
+        assert_eq!(replayed.len(), 4);
+        let cached = replayed[1].context.as_ref().unwrap();
+        assert_eq!(cached["cached"], "true");
+        assert_eq!(
+            request_fingerprint(&replayed),
+            request_fingerprint(&recorded)
+        );
+    }
+}
@@ -0,0 +1,313 @@
+use crate::conversable_agent::Message;
+use crate::llama_structs::{Content, ContentPart};
+use async_openai::types::Role;
+use serde::{Deserialize, Serialize};
+
+/// Rough token count, about four characters per token on Llama tokenizers.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Tokens a message takes in the prompt, including the role markers around it.
+pub fn estimate_message_tokens(message: &Message) -> usize {
+    const PER_MESSAGE_OVERHEAD: usize = 4;
+    // llava encodes an image as 576 patch embeddings
+    const PER_IMAGE: usize = 576;
+    match &message.content {
+        Some(Content::Parts(parts)) => {
+            PER_MESSAGE_OVERHEAD
+                + parts
+                    .iter()
+                    .map(|part| match part {
+                        ContentPart::Text(text) => estimate_tokens(text),
+                        ContentPart::ImageUrl(_) => PER_IMAGE,
+                    })
+                    .sum::<usize>()
+        }
+        _ => {
+            PER_MESSAGE_OVERHEAD
+                + message
+                    .content_to_string()
+                    .map_or(0, |text| estimate_tokens(&text))
+        }
+    }
+}
+
+/// Context window of the models we run locally, used when `context_limit` is
+/// unset. `None` for other models, whose requests are then sent untrimmed.
+pub fn default_context_limit(model: &str) -> Option<usize> {
+    let model = model.to_lowercase();
+    if model.contains("llama-3") || model.contains("llama3") {
+        Some(8192)
+    } else if model.contains("mistral") || model.contains("mixtral") {
+        Some(32768)
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TruncationStrategy {
+    /// Leave the removed turns out.
+    Drop,
+    /// Replace the removed turns with a single note saying what was left out.
+    Collapse,
+}
+
+/// `context` section of `LlmConfig`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ContextConfig {
+    /// Context window in tokens, `default_context_limit(model)` when unset.
+    /// Without either the history is never trimmed.
+    pub context_limit: Option<usize>,
+    /// Most recent non-system messages that are always sent.
+    pub keep_recent: usize,
+    pub strategy: TruncationStrategy,
+}
+
+impl Default for ContextConfig {
+    fn default() -> Self {
+        ContextConfig {
+            context_limit: None,
+            keep_recent: 2,
+            strategy: TruncationStrategy::Drop,
+        }
+    }
+}
+
+/// What `fit_to_context` left out of a request.
+#[derive(Debug, Clone, Default)]
+pub struct TruncationReport {
+    pub removed: Vec<Message>,
+    pub removed_tokens: usize,
+    pub kept_tokens: usize,
+}
+
+impl TruncationReport {
+    pub fn is_empty(&self) -> bool {
+        self.removed.is_empty()
+    }
+}
+
+/// Drops the oldest turns until `messages` fit in `budget` tokens.
+///
+/// System messages and the last `keep_recent` other messages always survive,
+/// even when they alone exceed the budget. A tool call and the results that
+/// follow it are kept or dropped together, servers reject a result whose call
+/// is missing.
+pub fn fit_to_context(
+    messages: Vec<Message>,
+    budget: usize,
+    keep_recent: usize,
+    strategy: &TruncationStrategy,
+) -> (Vec<Message>, TruncationReport) {
+    let is_system = |m: &Message| m.role == Some(Role::System);
+    let costs: Vec<usize> = messages.iter().map(estimate_message_tokens).collect();
+    let mut keep: Vec<bool> = messages.iter().map(is_system).collect();
+    let mut used: usize = costs
+        .iter()
+        .zip(&keep)
+        .filter(|(_, &kept)| kept)
+        .map(|(cost, _)| cost)
+        .sum();
+
+    // the other messages as `start..end` units, a call with its results
+    let mut units: Vec<(usize, usize)> = Vec::new();
+    for (i, message) in messages.iter().enumerate() {
+        if keep[i] {
+            continue;
+        }
+        let answers_call = matches!(message.content, Some(Content::ToolResult { .. }))
+            && units.last().is_some_and(|&(start, end)| {
+                end == i && matches!(messages[start].content, Some(Content::ToolCalls { .. }))
+            });
+        match units.last_mut() {
+            Some((_, end)) if answers_call => *end = i + 1,
+            _ => units.push((i, i + 1)),
+        }
+    }
+
+    // walk from the newest turn backwards, the recent ones are kept regardless
+    let mut seen = 0;
+    let mut full = false;
+    for &(start, end) in units.iter().rev() {
+        let cost: usize = costs[start..end].iter().sum();
+        if seen < keep_recent || (!full && used + cost <= budget) {
+            keep[start..end].iter_mut().for_each(|kept| *kept = true);
+            used += cost;
+        } else {
+            // stop at the first turn that doesn't fit so the history stays contiguous
+            full = true;
+        }
+        seen += end - start;
+    }
+
+    let mut report = TruncationReport {
+        kept_tokens: used,
+        ..Default::default()
+    };
+    let mut kept = Vec::new();
+    let mut note_at = None;
+    for ((message, kept_flag), cost) in messages.into_iter().zip(keep).zip(costs) {
+        if kept_flag {
+            kept.push(message);
+        } else {
+            note_at.get_or_insert(kept.len());
+            report.removed_tokens += cost;
+            report.removed.push(message);
+        }
+    }
+
+    if let (TruncationStrategy::Collapse, Some(at)) = (strategy, note_at) {
+        let note = Message::new(
+            Some(Content::Text(format!(
+                "[{} earlier messages (~{} tokens) omitted to fit the context window]",
+                report.removed.len(),
+                report.removed_tokens
+            ))),
+            None,
+            Some(Role::System),
+            None,
+        );
+        report.kept_tokens += estimate_message_tokens(&note);
+        kept.insert(at, note);
+    }
+
+    (kept, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llama_structs::ToolCall;
+    use crate::llm_config::LlmConfig;
+
+    fn history(turns: usize) -> Vec<Message> {
+        (0..turns)
+            .map(|i| {
+                Message::new(
+                    Some(Content::Text("word ".repeat(400) + &i.to_string())),
+                    None,
+                    Some(if i % 2 == 0 {
+                        Role::User
+                    } else {
+                        Role::Assistant
+                    }),
+                    None,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn unknown_models_are_not_trimmed() {
+        let config = LlmConfig {
+            model: "my-finetune".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(config.prompt_budget(), None);
+
+        let (kept, report) = config.fit_to_context(history(100));
+        assert_eq!(kept.len(), 100);
+        assert!(report.is_empty());
+    }
+
+    #[test]
+    fn configured_limits_are_applied_to_any_model() {
+        let mut config = LlmConfig {
+            model: "my-finetune".to_string(),
+            max_tokens: 500,
+            ..Default::default()
+        };
+        config.context.context_limit = Some(4096);
+        assert_eq!(config.prompt_budget(), Some(3596));
+
+        let (kept, report) = config.fit_to_context(history(100));
+        assert!(kept.len() < 100);
+        assert_eq!(kept.len() + report.removed.len(), 100);
+        assert!(report.kept_tokens <= 3596);
+    }
+
+    fn system(text: &str) -> Message {
+        Message::new(
+            Some(Content::Text(text.to_string())),
+            None,
+            Some(Role::System),
+            None,
+        )
+    }
+
+    #[test]
+    fn system_and_recent_messages_survive_any_budget() {
+        let mut messages = vec![system("You are terse.")];
+        messages.extend(history(10));
+
+        let (kept, report) = fit_to_context(messages.clone(), 10, 2, &TruncationStrategy::Drop);
+
+        assert_eq!(kept.len(), 3);
+        assert_eq!(kept[0].role, Some(Role::System));
+        assert_eq!(kept[1].id, messages[9].id);
+        assert_eq!(kept[2].id, messages[10].id);
+        assert_eq!(report.removed.len(), 8);
+        assert!(report.kept_tokens > 10);
+    }
+
+    #[test]
+    fn collapsed_turns_leave_a_note_in_their_place() {
+        let mut messages = vec![system("You are terse.")];
+        messages.extend(history(6));
+
+        let (kept, report) =
+            fit_to_context(messages.clone(), 1100, 1, &TruncationStrategy::Collapse);
+
+        assert_eq!(report.removed.len(), 4);
+        assert_eq!(kept.len(), 4);
+        assert_eq!(kept[0].id, messages[0].id);
+        assert_eq!(kept[1].role, Some(Role::System));
+        let note = kept[1].content_to_string().unwrap();
+        assert!(note.starts_with("[4 earlier messages"), "{}", note);
+        assert_eq!(kept[2].id, messages[5].id);
+        assert_eq!(kept[3].id, messages[6].id);
+    }
+
+    #[test]
+    fn tool_calls_and_their_results_are_dropped_together() {
+        let call = ToolCall::new("search", None);
+        let messages = vec![
+            Message::new(
+                Some(Content::ToolCalls {
+                    text: Some("word ".repeat(400)),
+                    calls: vec![call.clone()],
+                }),
+                None,
+                Some(Role::Assistant),
+                None,
+            ),
+            Message::tool_result(&call.id, "search", "three hits"),
+            Message::new(
+                Some(Content::Text("thanks".to_string())),
+                None,
+                Some(Role::User),
+                None,
+            ),
+        ];
+
+        let (kept, report) = fit_to_context(messages.clone(), 100, 1, &TruncationStrategy::Drop);
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].id, messages[2].id);
+        assert_eq!(report.removed.len(), 2);
+
+        // keeping the result keeps its call as well
+        let (kept, _) = fit_to_context(messages, 100, 2, &TruncationStrategy::Drop);
+        assert_eq!(kept.len(), 3);
+    }
+
+    #[test]
+    fn known_models_use_their_window() {
+        assert_eq!(default_context_limit("Hermes-2-Pro-Llama-3-8B"), Some(8192));
+        assert_eq!(default_context_limit("mistral-7b-instruct"), Some(32768));
+        assert_eq!(default_context_limit("phi-2"), None);
+    }
+}
@@ -1,22 +1,86 @@
 // use crate::exec_python::run_python;
+use crate::context_budget::TruncationReport;
 use crate::llama_structs::*;
-use crate::llm_llama_local::chat_inner_async_llama;
-use anyhow::anyhow;
-use async_openai::types::Role;
-use regex::Regex;
+use crate::llm_config::LlmConfig;
+use crate::llm_error::LlmError;
+use crate::llm_llama_local::{LlamaLocalClient, LlmClient, StreamChunk};
+use crate::model_fallback::{chat_stream_with_fallbacks, chat_with_fallbacks, FallbackRecord};
+use crate::turn_control::{TurnControl, TurnInterrupted};
+use crate::usage_ledger::{UsageLedger, UsageRecord, UsageSummary};
+use async_openai::types::{CompletionUsage, Role};
+use futures::{Stream, StreamExt};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
 
 type Context = HashMap<String, String>;
 
+/// One item of `ConversableAgent::a_generate_reply_stream`, the agent-level
+/// `StreamChunk`.
+#[derive(Debug, Clone)]
+pub enum ReplyChunk {
+    Delta(String),
+    /// The reply as a `Message` of this agent, as `a_generate_reply` returns it.
+    Done(Box<Message>),
+}
+
+pub type ReplyStream = Pin<Box<dyn Stream<Item = Result<ReplyChunk, LlmError>> + Send>>;
+
+fn new_message_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/// Milliseconds since the Unix epoch.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Creation order of messages in this process.
+fn next_message_seq() -> u64 {
+    static NEXT_SEQ: AtomicU64 = AtomicU64::new(0);
+    NEXT_SEQ.fetch_add(1, Ordering::Relaxed)
+}
+
+/// One turn of a conversation.
+///
+/// `context`, `id`, `created_at`, `seq`, `sender`, `recipient`, `parent_id` and
+/// `usage` are bookkeeping: they are never sent to the model, and they are left
+/// out of the request fingerprint, so cached and recorded replies still match.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message {
     pub content: Option<Content>,
     pub name: Option<String>,
     pub role: Option<Role>,
     pub context: Option<Context>,
+    #[serde(default = "new_message_id")]
+    pub id: String,
+    /// Milliseconds since the Unix epoch.
+    #[serde(default)]
+    pub created_at: u64,
+    /// Creation order within the process, orders messages with the same
+    /// `created_at`.
+    #[serde(default)]
+    pub seq: u64,
+    /// Name of the agent that wrote the message.
+    #[serde(default)]
+    pub sender: Option<String>,
+    /// Name of the agent the message was sent to.
+    #[serde(default)]
+    pub recipient: Option<String>,
+    /// Id of the message this one replies to.
+    #[serde(default)]
+    pub parent_id: Option<String>,
+    /// Tokens of the LLM call that produced the message.
+    #[serde(default)]
+    pub usage: Option<CompletionUsage>,
 }
 
 impl Default for Message {
@@ -26,11 +90,56 @@ impl Default for Message {
             name: None,
             role: None,
             context: None,
+            id: new_message_id(),
+            created_at: now_millis(),
+            seq: next_message_seq(),
+            sender: None,
+            recipient: None,
+            parent_id: None,
+            usage: None,
         }
     }
 }
 
 impl Message {
+    /// Marks this message as a reply to `parent`.
+    pub fn in_reply_to(mut self, parent: &Message) -> Self {
+        self.parent_id = Some(parent.id.clone());
+        self
+    }
+
+    /// Builds the `Role::Tool` reply to the tool call with id `call_id`.
+    pub fn tool_result(call_id: &str, name: &str, output: &str) -> Self {
+        Self::tool_result_with_status(call_id, name, output, false)
+    }
+
+    /// Like `tool_result`, for a tool that failed with the message `error`.
+    pub fn tool_error(call_id: &str, name: &str, error: &str) -> Self {
+        Self::tool_result_with_status(call_id, name, error, true)
+    }
+
+    fn tool_result_with_status(call_id: &str, name: &str, output: &str, is_error: bool) -> Self {
+        Message {
+            content: Some(Content::ToolResult {
+                call_id: call_id.to_string(),
+                name: name.to_string(),
+                output: output.to_string(),
+                is_error,
+            }),
+            name: Some(name.to_string()),
+            role: Some(Role::Tool),
+            ..Default::default()
+        }
+    }
+
+    /// Id of the call this message answers, for tool results.
+    pub fn tool_call_id(&self) -> Option<String> {
+        match &self.content {
+            Some(Content::ToolResult { call_id, .. }) => Some(call_id.clone()),
+            _ => None,
+        }
+    }
+
     pub fn new(
         content: Option<Content>,
         name: Option<String>,
@@ -42,6 +151,7 @@ impl Message {
             name,
             role: role.or(Some(Role::Assistant)), // Set default role to Assistant if None is provided
             context,
+            ..Default::default()
         }
     }
 }
@@ -67,6 +177,9 @@ pub struct ConversableAgent {
     pub default_auto_reply: Value,
     pub description: String,
     pub chat_messages: Option<Vec<Message>>,
+    pub llm_client: Arc<dyn LlmClient>,
+    pub usage_ledger: Arc<UsageLedger>,
+    pub conversation_id: Option<String>,
 }
 impl Clone for ConversableAgent {
     fn clone(&self) -> Self {
@@ -76,11 +189,14 @@ impl Clone for ConversableAgent {
             max_consecutive_auto_reply: self.max_consecutive_auto_reply,
             human_input_mode: self.human_input_mode.clone(),
             tool_calls_meta: self.tool_calls_meta.clone(),
-            in_tool_call: self.in_tool_call.clone(),
+            in_tool_call: self.in_tool_call,
             llm_config: self.llm_config.clone(),
             default_auto_reply: self.default_auto_reply.clone(),
             description: self.description.clone(),
             chat_messages: self.chat_messages.clone(),
+            llm_client: self.llm_client.clone(),
+            usage_ledger: self.usage_ledger.clone(),
+            conversation_id: self.conversation_id.clone(),
         }
     }
 }
@@ -102,6 +218,77 @@ impl Agent for ConversableAgent {
     }
 }
 
+/// What an agent knows about a reply before the model answers. Turns the
+/// answer into the agent's `Message` and records its usage.
+struct ReplyBuilder {
+    agent: String,
+    recipient: Option<String>,
+    parent_id: Option<String>,
+    conversation_id: Option<String>,
+    usage_ledger: Arc<UsageLedger>,
+}
+
+impl ReplyBuilder {
+    fn build(
+        &self,
+        output: LlamaResponseMessage,
+        llm_config: &LlmConfig,
+        fallbacks: &[FallbackRecord],
+        report: &TruncationReport,
+    ) -> Message {
+        let mut context = Context::new();
+        // replayed replies cost nothing, their usage was recorded when first made
+        if output.cached {
+            context.insert("cached".to_string(), "true".to_string());
+        } else {
+            self.usage_ledger.record(UsageRecord::new(
+                &self.agent,
+                &llm_config.model,
+                self.conversation_id.as_deref(),
+                &output.usage,
+            ));
+        }
+        if !fallbacks.is_empty() {
+            context.insert("model".to_string(), llm_config.model.clone());
+            context.insert(
+                "fallbacks".to_string(),
+                serde_json::to_string(fallbacks).unwrap_or_default(),
+            );
+        }
+        if output.is_truncated() {
+            context.insert("finish_reason".to_string(), "length".to_string());
+        }
+        if !report.is_empty() {
+            log::info!(
+                "{}: dropped {} earlier messages (~{} tokens) to fit the context window",
+                self.agent,
+                report.removed.len(),
+                report.removed_tokens
+            );
+            context.insert(
+                "truncated_messages".to_string(),
+                report.removed.len().to_string(),
+            );
+            context.insert(
+                "truncated_tokens".to_string(),
+                report.removed_tokens.to_string(),
+            );
+        }
+
+        Message {
+            content: Some(output.content),
+            name: None,
+            role: Some(Role::Assistant),
+            context: (!context.is_empty()).then_some(context),
+            sender: Some(self.agent.clone()),
+            recipient: self.recipient.clone(),
+            parent_id: self.parent_id.clone(),
+            usage: Some(output.usage),
+            ..Default::default()
+        }
+    }
+}
+
 impl ConversableAgent {
     pub fn new(name: &str) -> Self {
         ConversableAgent {
@@ -115,18 +302,53 @@ impl ConversableAgent {
             default_auto_reply: json!("this is user_proxy"),
             description: String::from("agent acting as user_proxy"),
             chat_messages: Some(vec![]),
+            llm_client: Arc::new(LlamaLocalClient::default()),
+            usage_ledger: Arc::new(UsageLedger::new()),
+            conversation_id: None,
+        }
+    }
+
+    pub fn set_llm_config(&mut self, llm_config: LlmConfig) {
+        self.llm_config = Some(llm_config.to_value());
+    }
+
+    /// Parses `llm_config`, falling back to the local server defaults when unset.
+    pub fn parsed_llm_config(&self) -> Result<LlmConfig, LlmError> {
+        match &self.llm_config {
+            Some(value) => {
+                LlmConfig::from_value(value).map_err(|e| LlmError::Config(e.to_string()))
+            }
+            None => Ok(LlmConfig::default()),
         }
     }
+
+    pub fn set_llm_client(&mut self, llm_client: Arc<dyn LlmClient>) {
+        self.llm_client = llm_client;
+    }
+
+    /// Shares `usage_ledger` with this agent, so several agents can report into one ledger.
+    pub fn set_usage_ledger(&mut self, usage_ledger: Arc<UsageLedger>) {
+        self.usage_ledger = usage_ledger;
+    }
+
+    /// Tokens and cost of the replies this agent generated.
+    pub fn usage_summary(&self) -> UsageSummary {
+        self.usage_ledger
+            .summary_where(|record| record.agent == self.name)
+    }
     pub async fn send(
         &self,
         message: Message,
         message_store: Arc<Mutex<HashMap<String, VecDeque<Message>>>>,
         recipient: Arc<Mutex<ConversableAgent>>,
-        request_reply: Option<bool>,
+        _request_reply: Option<bool>,
     ) {
         let agent_id = recipient.lock().unwrap().name.clone();
+        let mut message = message;
+        message.sender.get_or_insert_with(|| self.name.clone());
+        message.recipient = Some(agent_id.clone());
         let mut store = message_store.lock().unwrap();
-        let queue = store.entry(agent_id).or_insert_with(VecDeque::new);
+        let queue = store.entry(agent_id).or_default();
         queue.push_back(message);
     }
 
@@ -134,7 +356,7 @@ impl ConversableAgent {
         &self,
         message_store: Arc<Mutex<HashMap<String, VecDeque<Message>>>>,
         sender: Arc<Mutex<ConversableAgent>>,
-        request_reply: Option<bool>,
+        _request_reply: Option<bool>,
     ) -> Option<Message> {
         let agent_id = sender.lock().unwrap().name.clone();
         let store = message_store.lock().unwrap();
@@ -145,18 +367,135 @@ impl ConversableAgent {
         &self,
         messages: Vec<Message>,
         sender: Option<Arc<ConversableAgent>>,
-    ) -> Option<Message> {
-        let max_token = 1000u16;
-        let output: LlamaResponseMessage = chat_inner_async_llama(messages, max_token)
-            .await
-            .expect("Failed to generate reply");
+    ) -> Result<Message, LlmError> {
+        let llm_config = self.parsed_llm_config()?;
+        let builder = self.reply_builder(&messages, sender.as_deref());
+        let reply = chat_with_fallbacks(self.llm_client.as_ref(), messages, &llm_config).await?;
+        Ok(builder.build(
+            reply.reply,
+            &reply.llm_config,
+            &reply.fallbacks,
+            &reply.truncation,
+        ))
+    }
 
-        Some(Message {
-            content: Some(output.content),
-            name: None,
-            role: None,
-            context: None,
-        })
+    fn reply_builder(
+        &self,
+        messages: &[Message],
+        sender: Option<&ConversableAgent>,
+    ) -> ReplyBuilder {
+        ReplyBuilder {
+            agent: self.name.clone(),
+            recipient: sender.map(|agent| agent.name.clone()),
+            parent_id: messages.last().map(|m| m.id.clone()),
+            conversation_id: self.conversation_id.clone(),
+            usage_ledger: self.usage_ledger.clone(),
+        }
+    }
+
+    /// `a_generate_reply` under `control`. When the deadline passes first the
+    /// reply is `default_auto_reply`, marked with an `interrupted` context key.
+    pub async fn a_generate_reply_with_control(
+        &self,
+        messages: Vec<Message>,
+        sender: Option<Arc<ConversableAgent>>,
+        control: &TurnControl,
+    ) -> Result<Message, LlmError> {
+        match control.run(self.a_generate_reply(messages, sender)).await {
+            Ok(reply) => reply,
+            Err(TurnInterrupted::Cancelled) => Err(LlmError::Cancelled),
+            Err(TurnInterrupted::DeadlineExceeded) => {
+                log::warn!(
+                    "{}: no reply before the turn deadline, using default_auto_reply",
+                    self.name
+                );
+                Ok(self.default_reply_message())
+            }
+        }
+    }
+
+    fn default_reply_message(&self) -> Message {
+        let text = match &self.default_auto_reply {
+            Value::String(text) => text.clone(),
+            other => other.to_string(),
+        };
+        let mut context = Context::new();
+        context.insert("interrupted".to_string(), "deadline".to_string());
+        Message {
+            content: Some(Content::Text(text)),
+            name: Some(self.name.clone()),
+            role: Some(Role::Assistant),
+            context: Some(context),
+            sender: Some(self.name.clone()),
+            ..Default::default()
+        }
+    }
+
+    /// Runs `tool` for `call` under `control` and wraps its output as the tool
+    /// result. Failures and missed deadlines are reported to the model as text.
+    pub async fn a_execute_tool_call<F>(
+        &self,
+        call: &ToolCall,
+        tool: F,
+        control: &TurnControl,
+    ) -> Result<Message, LlmError>
+    where
+        F: Future<Output = anyhow::Result<String>>,
+    {
+        match control.run(tool).await {
+            Ok(Ok(output)) => Ok(Message::tool_result(&call.id, &call.name, &output)),
+            Ok(Err(e)) => Ok(Message::tool_error(&call.id, &call.name, &e.to_string())),
+            Err(TurnInterrupted::Cancelled) => Err(LlmError::Cancelled),
+            Err(TurnInterrupted::DeadlineExceeded) => Ok(Message::tool_error(
+                &call.id,
+                &call.name,
+                &format!("`{}` did not finish before the deadline", call.name),
+            )),
+        }
+    }
+
+    /// Trims `messages` to the prompt budget of `llm_config`, keeping the system
+    /// message and the most recent turns. The report lists what was removed;
+    /// nothing is when the model's context window is unknown.
+    pub fn fit_to_context(
+        &self,
+        messages: Vec<Message>,
+        llm_config: &LlmConfig,
+    ) -> (Vec<Message>, TruncationReport) {
+        llm_config.fit_to_context(messages)
+    }
+
+    /// Like `a_generate_reply`, but yields the reply text as it is generated.
+    /// The stream ends with `ReplyChunk::Done` carrying the same `Message`
+    /// `a_generate_reply` would have returned.
+    ///
+    /// Fallback endpoints are tried while the stream opens or fails on its
+    /// first chunk. Unlike `a_generate_reply`, transient errors are not retried
+    /// and a reply cut off at `max_tokens` is not continued: text already
+    /// yielded can't be taken back.
+    pub async fn a_generate_reply_stream(
+        &self,
+        messages: Vec<Message>,
+        sender: Option<Arc<ConversableAgent>>,
+    ) -> Result<ReplyStream, LlmError> {
+        let llm_config = self.parsed_llm_config()?;
+        let builder = self.reply_builder(&messages, sender.as_deref());
+        let reply =
+            chat_stream_with_fallbacks(self.llm_client.as_ref(), messages, &llm_config).await?;
+        let (llm_config, fallbacks, truncation) =
+            (reply.llm_config, reply.fallbacks, reply.truncation);
+
+        Ok(Box::pin(reply.reply.map(move |chunk| {
+            chunk.map(|chunk| match chunk {
+                StreamChunk::Delta(text) => ReplyChunk::Delta(text),
+                StreamChunk::Done(reply) => ReplyChunk::Done(Box::new(builder.build(
+                    reply,
+                    &llm_config,
+                    &fallbacks,
+                    &truncation,
+                ))),
+            })
+        })))
     }
 
     pub async fn update_system_message(&mut self, system_message: String) {
@@ -167,7 +506,7 @@ impl ConversableAgent {
         self.human_input_mode.clone()
     }
 
-    pub fn execute_code_blocks(&self, code_blocks: &str) -> String {
+    pub fn execute_code_blocks(&self, _code_blocks: &str) -> String {
         todo!()
         // match run_python(code_blocks) {
         //     Ok(res) => res,
@@ -199,3 +538,227 @@ impl ConversableAgent {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{user_message, HangingClient};
+    use async_openai::types::ChatCompletionRequestMessage;
+    use async_trait::async_trait;
+    use std::time::Duration;
+
+    /// Answers every request with `reply` and keeps the requests it saw.
+    struct CannedClient {
+        reply: String,
+        requests: Mutex<Vec<Vec<Message>>>,
+    }
+
+    impl CannedClient {
+        fn new(reply: &str) -> Arc<Self> {
+            Arc::new(CannedClient {
+                reply: reply.to_string(),
+                requests: Mutex::new(Vec::new()),
+            })
+        }
+    }
+
+    #[async_trait]
+    impl LlmClient for CannedClient {
+        async fn chat(
+            &self,
+            messages: Vec<Message>,
+            _llm_config: &LlmConfig,
+        ) -> Result<LlamaResponseMessage, LlmError> {
+            self.requests.lock().unwrap().push(messages);
+            Ok(LlamaResponseMessage::new(
+                Content::Text(self.reply.clone()),
+                Role::Assistant,
+                CompletionUsage {
+                    prompt_tokens: 10,
+                    completion_tokens: 2,
+                    total_tokens: 12,
+                },
+            ))
+        }
+    }
+
+    #[tokio::test]
+    async fn reply_comes_from_the_injected_client() {
+        let client = CannedClient::new("hi there");
+        let mut agent = ConversableAgent::new("assistant");
+        agent.set_llm_client(client.clone());
+        let question = user_message("hello");
+
+        let reply = agent
+            .a_generate_reply(vec![question.clone()], None)
+            .await
+            .unwrap();
+
+        assert_eq!(reply.content, Some(Content::Text("hi there".to_string())));
+        assert_eq!(reply.sender.as_deref(), Some("assistant"));
+        assert_eq!(reply.parent_id.as_deref(), Some(question.id.as_str()));
+        let requests = client.requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0][0].content, question.content);
+    }
+
+    #[tokio::test]
+    async fn replies_can_be_sent_back_as_history() {
+        let client = CannedClient::new("hi there");
+        let mut agent = ConversableAgent::new("assistant");
+        agent.set_llm_client(client.clone());
+        let question = user_message("hello");
+
+        let reply = agent
+            .a_generate_reply(vec![question.clone()], None)
+            .await
+            .unwrap();
+
+        assert_eq!(reply.role, Some(Role::Assistant));
+        assert!(matches!(
+            ChatCompletionRequestMessage::from(reply.clone()),
+            ChatCompletionRequestMessage::Assistant(_)
+        ));
+        assert!(matches!(
+            ChatCompletionRequestMessage::from(agent.default_reply_message()),
+            ChatCompletionRequestMessage::Assistant(_)
+        ));
+        agent
+            .a_generate_reply(vec![question, reply, user_message("again")], None)
+            .await
+            .unwrap();
+        assert_eq!(client.requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn streamed_reply_ends_with_the_agent_message() {
+        let mut agent = ConversableAgent::new("assistant");
+        agent.set_llm_client(CannedClient::new("streamed"));
+        let user = Arc::new(ConversableAgent::new("user"));
+
+        let stream = agent
+            .a_generate_reply_stream(vec![user_message("hello")], Some(user))
+            .await
+            .unwrap();
+        let chunks: Vec<ReplyChunk> = stream.map(Result::unwrap).collect().await;
+
+        assert!(matches!(&chunks[0], ReplyChunk::Delta(text) if text == "streamed"));
+        let Some(ReplyChunk::Done(reply)) = chunks.last() else {
+            panic!("stream did not end with Done");
+        };
+        assert_eq!(reply.content, Some(Content::Text("streamed".to_string())));
+        assert_eq!(reply.sender.as_deref(), Some("assistant"));
+        assert_eq!(reply.recipient.as_deref(), Some("user"));
+        assert_eq!(agent.usage_summary().total.completion_tokens, 2);
+    }
+
+    #[tokio::test]
+    async fn cached_replies_are_not_recorded_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = CannedClient::new("from the model");
+        let mut agent = ConversableAgent::new("assistant");
+        agent.set_llm_client(client.clone());
+        let mut llm_config = LlmConfig {
+            temperature: Some(0.0),
+            ..Default::default()
+        };
+        llm_config.cache.enabled = true;
+        llm_config.cache.dir = dir.path().to_path_buf();
+        agent.llm_config = Some(llm_config.to_value());
+
+        let question = user_message("hello");
+        let first = agent
+            .a_generate_reply(vec![question.clone()], None)
+            .await
+            .unwrap();
+        let second = agent.a_generate_reply(vec![question], None).await.unwrap();
+
+        assert_eq!(client.requests.lock().unwrap().len(), 1);
+        assert_eq!(first.content, second.content);
+        assert_eq!(
+            second.context.as_ref().and_then(|c| c.get("cached")),
+            Some(&"true".to_string())
+        );
+        assert_eq!(agent.usage_summary().total.completion_tokens, 2);
+    }
+
+    fn hanging_agent() -> ConversableAgent {
+        let mut agent = ConversableAgent::new("assistant");
+        agent.set_llm_client(Arc::new(HangingClient));
+        agent.default_auto_reply = json!("I'll get back to you.");
+        agent
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn missed_deadlines_reply_with_default_auto_reply() {
+        let agent = hanging_agent();
+        let control = TurnControl::with_timeout(Duration::from_secs(30));
+        let start = tokio::time::Instant::now();
+
+        let reply = agent
+            .a_generate_reply_with_control(vec![user_message("hello")], None, &control)
+            .await
+            .unwrap();
+
+        assert_eq!(start.elapsed(), Duration::from_secs(30));
+        assert_eq!(
+            reply.content,
+            Some(Content::Text("I'll get back to you.".to_string()))
+        );
+        assert_eq!(reply.context.unwrap()["interrupted"], "deadline");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cancelled_turns_fail() {
+        let agent = hanging_agent();
+        let control = TurnControl::new();
+        let canceller = control.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            canceller.cancel();
+        });
+
+        let reply = agent
+            .a_generate_reply_with_control(vec![user_message("hello")], None, &control)
+            .await;
+        assert!(matches!(reply, Err(LlmError::Cancelled)));
+
+        let call = ToolCall::new("search", None);
+        let result = agent
+            .a_execute_tool_call(&call, async { Ok("found".to_string()) }, &control)
+            .await;
+        assert!(matches!(result, Err(LlmError::Cancelled)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_tools_report_an_error_to_the_model() {
+        let agent = ConversableAgent::new("assistant");
+        let call = ToolCall::new("search", None);
+        let slow_search = async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok("found".to_string())
+        };
+
+        let result = agent
+            .a_execute_tool_call(
+                &call,
+                slow_search,
+                &TurnControl::with_timeout(Duration::from_secs(5)),
+            )
+            .await
+            .unwrap();
+
+        let Some(Content::ToolResult {
+            call_id,
+            output,
+            is_error,
+            ..
+        }) = result.content
+        else {
+            panic!("expected a tool result, got {:?}", result.content);
+        };
+        assert_eq!(call_id, call.id);
+        assert!(is_error);
+        assert!(output.contains("did not finish before the deadline"));
+    }
+}
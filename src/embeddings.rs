@@ -0,0 +1,210 @@
+use crate::llm_config::LlmConfig;
+use crate::llm_error::LlmError;
+use crate::llm_llama_local::LlmClient;
+use crate::request_limiter::acquire_permit;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// `embedding` section of `LlmConfig`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct EmbeddingConfig {
+    /// Embedding model, the chat `model` when unset.
+    pub model: Option<String>,
+    /// Inputs sent per request.
+    pub batch_size: usize,
+}
+
+impl Default for EmbeddingConfig {
+    fn default() -> Self {
+        EmbeddingConfig {
+            model: None,
+            batch_size: 32,
+        }
+    }
+}
+
+impl LlmConfig {
+    pub fn embedding_model(&self) -> &str {
+        self.embedding.model.as_deref().unwrap_or(&self.model)
+    }
+}
+
+/// Content hash of one input, scoped to the endpoint and model that embed it.
+/// Servers may load different weights under the same model name.
+pub fn embedding_key(api_base: &str, model: &str, input: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(api_base.as_bytes());
+    hasher.update([0]);
+    hasher.update(model.as_bytes());
+    hasher.update([0]);
+    hasher.update(input.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+/// Vectors stored as `<dir>/embeddings/<key>.json`.
+pub struct EmbeddingCache {
+    dir: PathBuf,
+}
+
+impl EmbeddingCache {
+    pub fn new(llm_config: &LlmConfig) -> Self {
+        EmbeddingCache {
+            dir: llm_config.cache.dir.join("embeddings"),
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+
+    pub fn get(&self, key: &str) -> Option<Vec<f32>> {
+        let data = std::fs::read_to_string(self.path(key)).ok()?;
+        serde_json::from_str(&data).ok()
+    }
+
+    pub fn put(&self, key: &str, vector: &[f32]) -> anyhow::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(self.path(key), serde_json::to_string(vector)?)?;
+        Ok(())
+    }
+}
+
+/// Embeds `inputs` with `client`, one vector per input in the same order.
+///
+/// With `llm_config.cache.enabled`, vectors are looked up by content hash and
+/// only the inputs not seen before are sent. Embeddings are deterministic, so
+/// the temperature rule of the reply cache does not apply.
+pub async fn embed_with_cache(
+    client: &dyn LlmClient,
+    inputs: Vec<String>,
+    llm_config: &LlmConfig,
+) -> Result<Vec<Vec<f32>>, LlmError> {
+    if inputs.is_empty() {
+        return Ok(Vec::new());
+    }
+    if !llm_config.cache.enabled {
+        let _permit = acquire_permit(llm_config).await;
+        return client.embed(inputs, llm_config).await;
+    }
+
+    let cache = EmbeddingCache::new(llm_config);
+    let model = llm_config.embedding_model();
+    let api_base = &llm_config.api_base;
+    let keys: Vec<String> = inputs
+        .iter()
+        .map(|i| embedding_key(api_base, model, i))
+        .collect();
+
+    let mut found: HashMap<String, Vec<f32>> = HashMap::new();
+    let mut missing = Vec::new();
+    for (key, input) in keys.iter().zip(&inputs) {
+        if found.contains_key(key) {
+            continue;
+        }
+        match cache.get(key) {
+            Some(vector) => {
+                found.insert(key.clone(), vector);
+            }
+            None if !missing.contains(input) => missing.push(input.clone()),
+            None => {}
+        }
+    }
+
+    if !missing.is_empty() {
+        let vectors = {
+            let _permit = acquire_permit(llm_config).await;
+            client.embed(missing.clone(), llm_config).await?
+        };
+        for (input, vector) in missing.iter().zip(vectors) {
+            let key = embedding_key(api_base, model, input);
+            if let Err(e) = cache.put(&key, &vector) {
+                log::warn!("Failed to write embedding cache entry {}: {}", key, e);
+            }
+            found.insert(key, vector);
+        }
+    }
+
+    keys.iter()
+        .map(|key| {
+            found
+                .get(key)
+                .cloned()
+                .ok_or_else(|| LlmError::Parse("fewer embeddings than inputs".to_string()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversable_agent::Message;
+    use crate::llama_structs::LlamaResponseMessage;
+    use crate::llm_config::ApiKeySource;
+    use async_trait::async_trait;
+    use std::sync::Mutex;
+
+    /// Embeds each input as its length and keeps the batches it was sent.
+    #[derive(Default)]
+    struct LengthEmbedder {
+        batches: Mutex<Vec<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl LlmClient for LengthEmbedder {
+        async fn chat(
+            &self,
+            _messages: Vec<Message>,
+            _llm_config: &LlmConfig,
+        ) -> Result<LlamaResponseMessage, LlmError> {
+            unimplemented!("only embeds")
+        }
+
+        async fn embed(
+            &self,
+            inputs: Vec<String>,
+            _llm_config: &LlmConfig,
+        ) -> Result<Vec<Vec<f32>>, LlmError> {
+            self.batches.lock().unwrap().push(inputs.clone());
+            Ok(inputs.iter().map(|i| vec![i.len() as f32]).collect())
+        }
+    }
+
+    fn strings(inputs: &[&str]) -> Vec<String> {
+        inputs.iter().map(|i| i.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn only_unseen_inputs_are_embedded() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut llm_config = LlmConfig {
+            api_key: ApiKeySource::None,
+            ..Default::default()
+        };
+        llm_config.cache.enabled = true;
+        llm_config.cache.dir = dir.path().to_path_buf();
+        let client = LengthEmbedder::default();
+
+        let vectors = embed_with_cache(&client, strings(&["a", "bb", "a"]), &llm_config)
+            .await
+            .unwrap();
+        assert_eq!(vectors, [vec![1.0], vec![2.0], vec![1.0]]);
+
+        let vectors = embed_with_cache(&client, strings(&["ccc", "bb"]), &llm_config)
+            .await
+            .unwrap();
+        assert_eq!(vectors, [vec![3.0], vec![2.0]]);
+
+        llm_config.api_base = "http://127.0.0.1:8081/v1".to_string();
+        embed_with_cache(&client, strings(&["a"]), &llm_config)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *client.batches.lock().unwrap(),
+            [strings(&["a", "bb"]), strings(&["ccc"]), strings(&["a"])]
+        );
+    }
+}
@@ -1,24 +1,119 @@
 use crate::conversable_agent::*;
+use crate::llm_error::LlmError;
+use crate::turn_control::TurnControl;
+use crate::usage_ledger::UsageSummary;
 use std::collections::{HashMap, VecDeque};
 use std::sync::{Arc, Mutex};
+use uuid::Uuid;
 
 pub struct GroupChat {
+    pub id: String,
     pub agents: HashMap<String, Arc<ConversableAgent>>,
     pub messages_store: Arc<Mutex<HashMap<String, VecDeque<Message>>>>,
     pub next_speaker: Option<String>,
 }
 
+impl Default for GroupChat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl GroupChat {
     pub fn new() -> Self {
         GroupChat {
+            id: Uuid::new_v4().to_string(),
             agents: HashMap::new(),
             messages_store: Arc::new(Mutex::new(HashMap::new())),
             next_speaker: None,
         }
     }
 
+    /// Adds a copy of `agent` whose LLM usage is recorded under this chat's `id`.
     pub fn register(&mut self, agent: &ConversableAgent) {
-        let agent_arc = Arc::new(agent.clone());
-        self.agents.insert(agent.name.clone(), agent_arc);
+        let mut agent = agent.clone();
+        agent.conversation_id = Some(self.id.clone());
+        self.agents.insert(agent.name.clone(), Arc::new(agent));
+    }
+
+    /// Lets `speaker` reply to `messages` and queues the reply under its name.
+    /// The whole round shares `control`, so one deadline bounds every turn in it.
+    pub async fn a_run_round(
+        &self,
+        speaker: &str,
+        messages: Vec<Message>,
+        control: &TurnControl,
+    ) -> Result<Message, LlmError> {
+        let agent = self
+            .agents
+            .get(speaker)
+            .ok_or_else(|| LlmError::Config(format!("no agent named {} in this chat", speaker)))?;
+        let reply = agent
+            .a_generate_reply_with_control(messages, None, &control.child(None))
+            .await?;
+
+        self.messages_store
+            .lock()
+            .unwrap()
+            .entry(speaker.to_string())
+            .or_default()
+            .push_back(reply.clone());
+        Ok(reply)
+    }
+
+    /// Tokens and cost of this chat, across every ledger its agents report into.
+    pub fn usage_summary(&self) -> UsageSummary {
+        let mut ledgers: Vec<_> = Vec::new();
+        for agent in self.agents.values() {
+            if !ledgers.iter().any(|l| Arc::ptr_eq(l, &agent.usage_ledger)) {
+                ledgers.push(agent.usage_ledger.clone());
+            }
+        }
+
+        let mut summary = UsageSummary::default();
+        for ledger in ledgers {
+            summary.merge(
+                &ledger.summary_where(|record| record.conversation.as_deref() == Some(&self.id)),
+            );
+        }
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llama_structs::Content;
+    use crate::test_server::{user_message, HangingClient};
+    use serde_json::json;
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn rounds_past_the_deadline_queue_the_default_reply() {
+        let mut agent = ConversableAgent::new("assistant");
+        agent.set_llm_client(Arc::new(HangingClient));
+        agent.default_auto_reply = json!("Out of time.");
+        let mut chat = GroupChat::new();
+        chat.register(&agent);
+
+        let control = TurnControl::with_timeout(Duration::from_secs(10));
+        let reply = chat
+            .a_run_round("assistant", vec![user_message("hello")], &control)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            reply.content,
+            Some(Content::Text("Out of time.".to_string()))
+        );
+        let stored = chat.messages_store.lock().unwrap()["assistant"].clone();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].id, reply.id);
+
+        control.cancel();
+        let cancelled = chat
+            .a_run_round("assistant", vec![user_message("hello")], &control)
+            .await;
+        assert!(matches!(cancelled, Err(LlmError::Cancelled)));
     }
 }
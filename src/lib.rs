@@ -1,9 +1,25 @@
 // pub mod conversable_agent;
+pub mod embeddings;
 // pub mod groupchat;
+pub mod cassette;
+pub mod context_budget;
 pub mod conversable_agent;
 // pub mod exec_python;
 pub mod llama_structs;
+pub mod llm_config;
+pub mod llm_error;
 pub mod llm_llama_local;
+pub mod llm_ollama;
+pub mod model_fallback;
+pub mod prompt_template;
+pub mod request_limiter;
+pub mod response_cache;
+pub mod structured_output;
+pub mod transcript;
+pub mod turn_control;
+pub mod usage_ledger;
 pub mod webscraper_hook;
 pub mod groupchat;
-// pub mod tool_call_actuators;
\ No newline at end of file
+// pub mod tool_call_actuators;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_server;
@@ -1,65 +1,439 @@
-use async_openai::types::{CompletionUsage, CreateChatCompletionResponse, Role};
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionTokenLogprob, CompletionUsage,
+    CreateChatCompletionResponse, FinishReason, Role,
+};
+use base64::Engine;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde_json::{json, Map, Value};
+use std::path::Path;
+use uuid::Uuid;
 
-use crate::{llm_llama_local::chat_inner_async, webscraper_hook::get_webpage_text};
+use crate::llm_llama_local::chat_inner_async;
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct ToolCall {
+    /// Assigned by servers with native function calling, generated for Hermes
+    /// `<tool_call>` blocks. Tool results refer back to it.
+    #[serde(default = "generate_call_id")]
+    pub id: String,
     pub name: String,
-    pub arguments: Option<HashMap<String, String>>,
+    /// The JSON object the model passed, with values of any JSON type.
+    pub arguments: Option<Map<String, Value>>,
+    /// The argument string exactly as the server sent it, when it was not a
+    /// JSON object. `arguments` is then `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_arguments: Option<String>,
+    /// The reply text the call was read from, e.g. a Hermes `<tool_call>`
+    /// block. `None` for calls the server returned natively.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_text: Option<String>,
+}
+
+/// A fresh call id in the OpenAI `call_...` form.
+pub fn generate_call_id() -> String {
+    format!("call_{}", Uuid::new_v4().simple())
+}
+
+impl ToolCall {
+    pub fn new(name: &str, arguments: Option<Map<String, Value>>) -> Self {
+        ToolCall {
+            id: generate_call_id(),
+            name: name.to_string(),
+            arguments,
+            raw_arguments: None,
+            source_text: None,
+        }
+    }
+
+    pub fn argument(&self, key: &str) -> Option<&Value> {
+        self.arguments.as_ref()?.get(key)
+    }
+
+    pub fn str_argument(&self, key: &str) -> Option<&str> {
+        self.argument(key)?.as_str()
+    }
+
+    pub fn i64_argument(&self, key: &str) -> Option<i64> {
+        self.argument(key)?.as_i64()
+    }
+
+    pub fn f64_argument(&self, key: &str) -> Option<f64> {
+        self.argument(key)?.as_f64()
+    }
+
+    pub fn bool_argument(&self, key: &str) -> Option<bool> {
+        self.argument(key)?.as_bool()
+    }
+
+    /// Deserializes the arguments into `T`, missing arguments read as `{}`.
+    /// Malformed `raw_arguments` fail with their parse error.
+    pub fn arguments_as<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        match &self.raw_arguments {
+            Some(raw) => serde_json::from_str(raw),
+            None => {
+                serde_json::from_value(Value::Object(self.arguments.clone().unwrap_or_default()))
+            }
+        }
+    }
+
+    /// The arguments as a JSON object string, `{}` when there are none.
+    /// Malformed arguments are returned as they were sent.
+    pub fn arguments_json(&self) -> String {
+        match (&self.arguments, &self.raw_arguments) {
+            (Some(arguments), _) => Value::Object(arguments.clone()).to_string(),
+            (None, Some(raw)) => raw.clone(),
+            (None, None) => "{}".to_string(),
+        }
+    }
+}
+
+impl From<ChatCompletionMessageToolCall> for ToolCall {
+    fn from(call: ChatCompletionMessageToolCall) -> ToolCall {
+        // arguments arrive as a JSON string, which models sometimes get wrong
+        let raw = call.function.arguments;
+        let (arguments, raw_arguments) = if raw.trim().is_empty() {
+            (None, None)
+        } else {
+            match serde_json::from_str::<Map<String, Value>>(&raw) {
+                Ok(arguments) => (Some(arguments), None),
+                Err(_) => (None, Some(raw)),
+            }
+        };
+
+        ToolCall {
+            // some llama.cpp builds send an empty id
+            id: if call.id.is_empty() {
+                generate_call_id()
+            } else {
+                call.id
+            },
+            name: call.function.name,
+            arguments,
+            raw_arguments,
+            source_text: None,
+        }
+    }
+}
+
+/// A tool the model may call natively, sent as `tools` in the chat request.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ToolDefinition {
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// JSON schema of the arguments object.
+    pub parameters: Value,
+}
+
+/// One piece of a multimodal message.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub enum ContentPart {
+    Text(String),
+    /// An `http(s)` URL, or a `data:` URL with the image inline.
+    ImageUrl(String),
+}
+
+impl ContentPart {
+    pub fn text(text: &str) -> Self {
+        ContentPart::Text(text.to_string())
+    }
+
+    pub fn image_url(url: &str) -> Self {
+        ContentPart::ImageUrl(url.to_string())
+    }
+
+    /// Reads an image from disk into a base64 `data:` URL, so local servers
+    /// don't need access to the file.
+    pub fn image_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        let mime = match extension.as_str() {
+            "png" => "image/png",
+            "jpg" | "jpeg" => "image/jpeg",
+            "gif" => "image/gif",
+            "webp" => "image/webp",
+            "bmp" => "image/bmp",
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "unsupported image type: {}",
+                    path.display()
+                ))
+            }
+        };
+        let bytes = std::fs::read(path)?;
+        Ok(ContentPart::ImageUrl(format!(
+            "data:{};base64,{}",
+            mime,
+            base64::engine::general_purpose::STANDARD.encode(bytes)
+        )))
+    }
+
+    /// The base64 payload of a `data:` image URL.
+    pub fn inline_image_data(&self) -> Option<&str> {
+        match self {
+            ContentPart::ImageUrl(url) if url.starts_with("data:") => {
+                url.split_once(";base64,").map(|(_, data)| data)
+            }
+            _ => None,
+        }
+    }
 }
 
 #[allow(non_snake_case)]
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(from = "StoredContent")]
 pub enum Content {
     Text(String),
+    /// Calls requested by the model, with any prose it wrote around them.
+    ToolCalls {
+        text: Option<String>,
+        calls: Vec<ToolCall>,
+    },
+    /// Text and images, for vision models such as llava.
+    Parts(Vec<ContentPart>),
+    /// Output of the tool call with id `call_id`.
+    ToolResult {
+        call_id: String,
+        name: String,
+        output: String,
+        is_error: bool,
+    },
+}
+
+/// What `Content` is read from: the current variants plus the single
+/// `ToolCall` variant that older cassettes and transcripts were written with.
+#[derive(Deserialize)]
+enum StoredContent {
+    Text(String),
+    ToolCalls {
+        text: Option<String>,
+        calls: Vec<ToolCall>,
+    },
+    Parts(Vec<ContentPart>),
+    ToolResult {
+        call_id: String,
+        name: String,
+        output: String,
+        is_error: bool,
+    },
     ToolCall(ToolCall),
 }
 
+impl From<StoredContent> for Content {
+    fn from(stored: StoredContent) -> Content {
+        match stored {
+            StoredContent::Text(text) => Content::Text(text),
+            StoredContent::ToolCalls { text, calls } => Content::ToolCalls { text, calls },
+            StoredContent::Parts(parts) => Content::Parts(parts),
+            StoredContent::ToolResult {
+                call_id,
+                name,
+                output,
+                is_error,
+            } => Content::ToolResult {
+                call_id,
+                name,
+                output,
+                is_error,
+            },
+            StoredContent::ToolCall(call) => Content::ToolCalls {
+                text: None,
+                calls: vec![call],
+            },
+        }
+    }
+}
+
+/// Log probability of one generated token, with the likeliest alternatives.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct TokenLogprob {
+    pub token: String,
+    pub logprob: f32,
+    #[serde(default)]
+    pub top_logprobs: Vec<(String, f32)>,
+}
+
+impl From<ChatCompletionTokenLogprob> for TokenLogprob {
+    fn from(logprob: ChatCompletionTokenLogprob) -> TokenLogprob {
+        TokenLogprob {
+            token: logprob.token,
+            logprob: logprob.logprob,
+            top_logprobs: logprob
+                .top_logprobs
+                .into_iter()
+                .map(|top| (top.token, top.logprob))
+                .collect(),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct LlamaResponseMessage {
     pub content: Content,
     pub role: Role,
     pub usage: CompletionUsage,
+    /// Why generation stopped, `Length` when the reply ran into `max_tokens`.
+    #[serde(default)]
+    pub finish_reason: Option<FinishReason>,
+    /// Present when requested with `LlmConfig.logprobs`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<Vec<TokenLogprob>>,
+    /// Served from the response cache or a cassette, no tokens were spent.
+    /// `usage` still holds what the original call used.
+    #[serde(skip)]
+    pub cached: bool,
+}
+
+impl LlamaResponseMessage {
+    pub fn new(content: Content, role: Role, usage: CompletionUsage) -> Self {
+        LlamaResponseMessage {
+            content,
+            role,
+            usage,
+            finish_reason: None,
+            logprobs: None,
+            cached: false,
+        }
+    }
+
+    /// Whether the reply was cut off by the token limit.
+    pub fn is_truncated(&self) -> bool {
+        self.finish_reason == Some(FinishReason::Length)
+    }
 }
 
-fn extract_json_from_xml_like(xml_like_data: &str) -> Option<String> {
-    let start_tag = "<tool_call>";
-    let end_tag = "</tool_call>";
+impl Content {
+    /// Wraps calls without any accompanying text.
+    pub fn from_calls(calls: Vec<ToolCall>) -> Self {
+        Content::ToolCalls { text: None, calls }
+    }
+
+    /// The tool calls in this content, empty for anything but `ToolCalls`.
+    pub fn tool_calls(&self) -> &[ToolCall] {
+        match self {
+            Content::ToolCalls { calls, .. } => calls,
+            _ => &[],
+        }
+    }
+}
 
-    if xml_like_data.trim().starts_with(start_tag) && xml_like_data.trim().ends_with(end_tag) {
-        let start_pos = start_tag.len();
-        let end_pos = xml_like_data.len() - end_tag.len();
-        Some(xml_like_data[start_pos..end_pos].trim().to_string())
+/// Text of a tool result as the model should read it.
+pub fn tool_output_text(output: &str, is_error: bool) -> String {
+    if is_error {
+        format!("error: {}", output)
     } else {
-        None
+        output.to_string()
+    }
+}
+
+/// A tool result in the Hermes convention, a `<tool_response>` block in a tool turn.
+pub fn hermes_tool_response(name: &str, output: &str, is_error: bool) -> String {
+    format!(
+        "<tool_response>\n{}\n</tool_response>",
+        json!({ "name": name, "content": tool_output_text(output, is_error) })
+    )
+}
+
+/// Cuts every well-formed `<tool_call>` block out of `data`. Returns the text
+/// left around them and the calls in order; malformed blocks stay in the text.
+fn split_tool_call_blocks(data: &str) -> (String, Vec<ToolCall>) {
+    const START_TAG: &str = "<tool_call>";
+    const END_TAG: &str = "</tool_call>";
+
+    let mut text = String::new();
+    let mut calls = Vec::new();
+    let mut rest = data;
+    while let Some(start) = rest.find(START_TAG) {
+        let body = &rest[start + START_TAG.len()..];
+        // an unclosed block, e.g. cut off at max_tokens, is left as text
+        let Some(end) = body.find(END_TAG) else {
+            break;
+        };
+        let block_end = start + START_TAG.len() + end + END_TAG.len();
+        match tool_call_from_json(body[..end].trim()) {
+            Some(mut call) => {
+                text.push_str(&rest[..start]);
+                call.source_text = Some(rest[start..block_end].to_string());
+                calls.push(call);
+            }
+            None => text.push_str(&rest[..block_end]),
+        }
+        rest = &rest[block_end..];
+    }
+    text.push_str(rest);
+    (text, calls)
+}
+
+/// Reads a `{"name": ..., "arguments": {...}}` object, `None` if the JSON is malformed.
+pub fn tool_call_from_json(json_str: &str) -> Option<ToolCall> {
+    let mut obj = match serde_json::from_str::<Value>(json_str).ok()? {
+        Value::Object(obj) => obj,
+        _ => return None,
+    };
+    let name = obj.get("name")?.as_str()?.to_string();
+    let arguments = match obj.remove("arguments") {
+        Some(Value::Object(map)) => Some(map),
+        Some(Value::Null) | None => None,
+        Some(_) => return None,
+    };
+    Some(ToolCall {
+        id: generate_call_id(),
+        name,
+        arguments,
+        raw_arguments: None,
+        source_text: Some(json_str.to_string()),
+    })
+}
+
+/// Turns the raw text of a reply into `Content`, recognising every Hermes
+/// `<tool_call>` block in it. A block with malformed JSON is kept as text
+/// rather than failing the reply.
+pub fn parse_llama_content(data: &str) -> Content {
+    let (text, calls) = split_tool_call_blocks(data);
+    if calls.is_empty() {
+        return Content::Text(data.to_owned());
+    }
+    let text = text.trim();
+    Content::ToolCalls {
+        text: (!text.is_empty()).then(|| text.to_string()),
+        calls,
     }
 }
 
 pub fn output_llama_response(
     res_obj: CreateChatCompletionResponse,
 ) -> Option<LlamaResponseMessage> {
-    let usage = res_obj.clone().usage.unwrap();
-    let msg_obj = res_obj.clone().choices[0].message.clone();
+    let usage = res_obj.usage.clone().unwrap_or(CompletionUsage {
+        prompt_tokens: 0,
+        completion_tokens: 0,
+        total_tokens: 0,
+    });
+    let choice = res_obj.choices.first()?.clone();
+    let msg_obj = choice.message;
     let role = msg_obj.clone().role;
-    if let Some(data) = msg_obj.content {
-        if let Some(json_str) = extract_json_from_xml_like(&data) {
-            let tool_call: ToolCall = serde_json::from_str(&json_str).unwrap();
-            return Some(LlamaResponseMessage {
-                content: Content::ToolCall(tool_call),
-                role: role,
-                usage: usage,
-            });
-        } else {
-            return Some(LlamaResponseMessage {
-                content: Content::Text(data.to_owned()),
-                role: role,
-                usage: usage,
-            });
+    let content = if let Some(tool_calls) = msg_obj.tool_calls.filter(|calls| !calls.is_empty()) {
+        Content::ToolCalls {
+            text: msg_obj.content.filter(|text| !text.trim().is_empty()),
+            calls: tool_calls.into_iter().map(ToolCall::from).collect(),
         }
-    }
-    None
+    } else if let Some(data) = msg_obj.content {
+        parse_llama_content(&data)
+    } else {
+        return None;
+    };
+
+    let mut response = LlamaResponseMessage::new(content, role, usage);
+    response.finish_reason = choice.finish_reason;
+    response.logprobs = choice
+        .logprobs
+        .and_then(|logprobs| logprobs.content)
+        .map(|tokens| tokens.into_iter().map(TokenLogprob::from).collect());
+    Some(response)
 }
 
 pub async fn fire_tool_call(
@@ -67,7 +441,7 @@ pub async fn fire_tool_call(
     // tool_call_obj: &str,
     user_prompt: &str,
 ) -> anyhow::Result<LlamaResponseMessage> {
-    let system_prompt = r#"<|im_start|>system You are a function calling AI model. You are provided with function signatures within <tools></tools> XML tags. You may call one or more functions to assist with the user query. Don't make assumptions about what values to plug into functions. Here are the available tools: <tools> 
+    let system_prompt = r#"You are a function calling AI model. You are provided with function signatures within <tools></tools> XML tags. You may call one or more functions to assist with the user query. Don't make assumptions about what values to plug into functions. Here are the available tools: <tools> 
     
     The function `get_webpage_text` retrieves all text content from a given URL. For example, calling `get_webpage_text("https://example.com")` will fetch the text from Example.com.
     
@@ -118,7 +492,7 @@ pub async fn fire_tool_call(
     //     "getTimeOfDay" => get_time_of_day(),
     //     _ => "".to_string(),
     // };
-    let res = chat_inner_async(&system_prompt, &user_prompt, 500).await?;
+    let _res = chat_inner_async(&system_prompt, user_prompt, 500).await?;
 
     // if let Some(parsed) = output_llama_response(res) {
     //     match parsed.content {
@@ -139,3 +513,164 @@ pub async fn fire_tool_call(
     // }
     Err(anyhow::Error::msg("parsing error"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_openai::types::{ChatCompletionToolType, FunctionCall};
+
+    #[test]
+    fn image_files_round_trip_through_data_urls() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pixel.PNG");
+        let bytes = b"\x89PNG\r\n\x1a\nnot really an image";
+        std::fs::write(&path, bytes).unwrap();
+
+        let part = ContentPart::image_file(&path).unwrap();
+        let ContentPart::ImageUrl(url) = &part else {
+            panic!("expected an image, got {:?}", part);
+        };
+        assert!(url.starts_with("data:image/png;base64,"));
+        let data = part.inline_image_data().unwrap();
+        assert_eq!(
+            base64::engine::general_purpose::STANDARD
+                .decode(data)
+                .unwrap(),
+            bytes
+        );
+
+        assert!(ContentPart::image_file(dir.path().join("notes.txt")).is_err());
+        assert_eq!(
+            ContentPart::image_url("https://example.com/cat.png").inline_image_data(),
+            None
+        );
+        assert_eq!(ContentPart::text("a cat").inline_image_data(), None);
+    }
+
+    const SEARCH_BLOCK: &str =
+        r#"<tool_call>{"name": "search", "arguments": {"query": "rust"}}</tool_call>"#;
+    const CLOCK_BLOCK: &str = r#"<tool_call>{"name": "clock"}</tool_call>"#;
+
+    #[test]
+    fn every_tool_call_block_is_read_in_order() {
+        let reply = format!("Let me look.\n{}\nand then\n{}", SEARCH_BLOCK, CLOCK_BLOCK);
+        let Content::ToolCalls { text, calls } = parse_llama_content(&reply) else {
+            panic!("expected tool calls");
+        };
+
+        assert_eq!(text.as_deref(), Some("Let me look.\n\nand then"));
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].name, "search");
+        assert_eq!(calls[0].str_argument("query"), Some("rust"));
+        assert_eq!(calls[0].source_text.as_deref(), Some(SEARCH_BLOCK));
+        assert_eq!(calls[1].name, "clock");
+        assert_eq!(calls[1].arguments, None);
+        assert_eq!(calls[1].source_text.as_deref(), Some(CLOCK_BLOCK));
+        assert_ne!(calls[0].id, calls[1].id);
+    }
+
+    #[test]
+    fn malformed_blocks_stay_in_the_text() {
+        let malformed = r#"<tool_call>{"name": "search", "arguments": </tool_call>"#;
+        let reply = format!("{}\n{}", malformed, CLOCK_BLOCK);
+        let Content::ToolCalls { text, calls } = parse_llama_content(&reply) else {
+            panic!("expected tool calls");
+        };
+        assert_eq!(text.as_deref(), Some(malformed));
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].name, "clock");
+
+        // without a well-formed block the reply is plain text
+        assert_eq!(
+            parse_llama_content(malformed),
+            Content::Text(malformed.to_string())
+        );
+    }
+
+    #[test]
+    fn unclosed_blocks_stay_in_the_text() {
+        let unclosed = r#"<tool_call>{"name": "search", "argu"#;
+        let reply = format!("{}\n{}", CLOCK_BLOCK, unclosed);
+        let (text, calls) = split_tool_call_blocks(&reply);
+        assert_eq!(text, format!("\n{}", unclosed));
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].name, "clock");
+
+        assert_eq!(
+            parse_llama_content(unclosed),
+            Content::Text(unclosed.to_string())
+        );
+    }
+
+    fn native_call(arguments: &str) -> ToolCall {
+        ToolCall::from(ChatCompletionMessageToolCall {
+            id: "call_1".to_string(),
+            r#type: ChatCompletionToolType::Function,
+            function: FunctionCall {
+                name: "forecast".to_string(),
+                arguments: arguments.to_string(),
+            },
+        })
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Forecast {
+        city: String,
+        days: u32,
+        #[serde(default)]
+        hourly: bool,
+    }
+
+    #[test]
+    fn arguments_are_read_by_type() {
+        let call = native_call(r#"{"city": "Oslo", "days": 3, "hourly": true, "min": -2.5}"#);
+
+        assert_eq!(call.argument("days"), Some(&json!(3)));
+        assert_eq!(call.str_argument("city"), Some("Oslo"));
+        assert_eq!(call.i64_argument("days"), Some(3));
+        assert_eq!(call.f64_argument("min"), Some(-2.5));
+        assert_eq!(call.bool_argument("hourly"), Some(true));
+        // a value of another type reads as missing
+        assert_eq!(call.i64_argument("city"), None);
+        assert_eq!(call.str_argument("country"), None);
+
+        assert_eq!(
+            call.arguments_as::<Forecast>().unwrap(),
+            Forecast {
+                city: "Oslo".to_string(),
+                days: 3,
+                hourly: true,
+            }
+        );
+        assert!(native_call(r#"{"city": "Oslo"}"#)
+            .arguments_as::<Forecast>()
+            .is_err());
+    }
+
+    #[test]
+    fn calls_without_arguments_read_as_an_empty_object() {
+        let call = native_call("");
+        assert_eq!((call.arguments, call.raw_arguments), (None, None));
+
+        let call = ToolCall::new("clock", None);
+        assert_eq!(call.argument("zone"), None);
+        assert_eq!(call.arguments_json(), "{}");
+        assert_eq!(
+            call.arguments_as::<Map<String, Value>>().unwrap(),
+            Map::new()
+        );
+    }
+
+    #[test]
+    fn malformed_arguments_are_kept_as_sent() {
+        let raw = r#"{"city": "Oslo", "days": "#;
+        let call = native_call(raw);
+
+        assert_eq!(call.arguments, None);
+        assert_eq!(call.raw_arguments.as_deref(), Some(raw));
+        assert_eq!(call.str_argument("city"), None);
+        assert_eq!(call.arguments_json(), raw);
+        let error = call.arguments_as::<Forecast>().unwrap_err();
+        assert!(error.is_eof(), "{}", error);
+    }
+}
@@ -0,0 +1,247 @@
+use crate::context_budget::{
+    default_context_limit, estimate_message_tokens, fit_to_context, ContextConfig, TruncationReport,
+};
+use crate::conversable_agent::Message;
+use crate::embeddings::EmbeddingConfig;
+use crate::llama_structs::ToolDefinition;
+use crate::llm_ollama::OllamaOptions;
+use crate::model_fallback::ModelEndpoint;
+use crate::prompt_template::PromptTemplate;
+use crate::request_limiter::ConcurrencyConfig;
+use crate::response_cache::CacheConfig;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+use std::time::Duration;
+
+/// Where the bearer token for the LLM server comes from.
+#[derive(Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiKeySource {
+    /// Read the key from the named environment variable, once per client.
+    Env(String),
+    /// Use the given key as is.
+    Value(String),
+    /// Send no key; most local servers don't check it.
+    None,
+}
+
+impl fmt::Debug for ApiKeySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiKeySource::Env(var) => f.debug_tuple("Env").field(var).finish(),
+            ApiKeySource::Value(_) => f.debug_tuple("Value").field(&"<redacted>").finish(),
+            ApiKeySource::None => f.write_str("None"),
+        }
+    }
+}
+
+impl ApiKeySource {
+    pub fn resolve(&self) -> anyhow::Result<String> {
+        match self {
+            ApiKeySource::Env(var) => {
+                std::env::var(var).map_err(|_| anyhow::anyhow!("{} must be set", var))
+            }
+            ApiKeySource::Value(key) => Ok(key.clone()),
+            ApiKeySource::None => Ok(String::new()),
+        }
+    }
+}
+
+/// Exponential backoff for transient LLM errors.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// Retries after the first attempt, 0 disables retrying.
+    pub max_retries: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub multiplier: f64,
+    /// Sleep a random duration up to the computed backoff ("full jitter").
+    pub jitter: bool,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_retries: 3,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 10_000,
+            multiplier: 2.0,
+            jitter: true,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Delay before retry number `attempt`, counting from 0.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self.initial_backoff_ms as f64 * self.multiplier.powi(attempt as i32);
+        let capped = exp.min(self.max_backoff_ms as f64) as u64;
+        if self.jitter && capped > 0 {
+            Duration::from_millis(rand::thread_rng().gen_range(0..=capped))
+        } else {
+            Duration::from_millis(capped)
+        }
+    }
+}
+
+/// Typed view of `ConversableAgent.llm_config`.
+///
+/// Every field is optional in the JSON form, missing ones fall back to the
+/// local Hermes server defaults, e.g.
+/// `{"model": "Hermes-2-Pro-Llama-3-8B", "temperature": 0.2, "max_tokens": 512}`.
+/// `api_key` is one of `{"env": "LLAMA_API_KEY"}`, `{"value": "sk-..."}` or
+/// `"none"`, e.g.
+/// `{"api_base": "https://api.openai.com/v1", "model": "gpt-4o", "api_key": {"env": "OPENAI_API_KEY"}}`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct LlmConfig {
+    pub api_base: String,
+    pub model: String,
+    pub api_key: ApiKeySource,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: u16,
+    pub stop: Vec<String>,
+    pub seed: Option<i64>,
+    /// Per-request timeout in seconds, no limit when unset.
+    pub timeout_secs: Option<u64>,
+    /// Tools offered to servers with native function calling.
+    pub tools: Vec<ToolDefinition>,
+    /// Ask the server for a JSON object reply (`response_format` / Ollama `format`).
+    pub json_mode: bool,
+    /// Ask for the log probability of each token and this many alternatives.
+    pub logprobs: Option<u8>,
+    /// Times to ask the model to go on when a reply stops at `max_tokens`.
+    pub max_continuations: u32,
+    /// Constrain replies to valid calls of `tools` with a llama.cpp `json_schema`.
+    pub constrain_tool_calls: bool,
+    pub retry: RetryConfig,
+    pub cache: CacheConfig,
+    pub context: ContextConfig,
+    pub concurrency: ConcurrencyConfig,
+    /// Endpoints tried in order when this one refuses the request or its
+    /// context is too small.
+    pub fallbacks: Vec<ModelEndpoint>,
+    pub embedding: EmbeddingConfig,
+    /// Prompt format used by `LlamaCompletionClient`.
+    pub prompt_template: PromptTemplate,
+    /// Extra options for `OllamaClient`.
+    pub ollama: OllamaOptions,
+}
+
+impl Default for LlmConfig {
+    fn default() -> Self {
+        LlmConfig {
+            api_base: String::from("http://127.0.0.1:8080/v1"),
+            model: String::from("Hermes-2-Pro-Llama-3-8B"),
+            api_key: ApiKeySource::Env(String::from("LLAMA_API_KEY")),
+            temperature: None,
+            top_p: None,
+            max_tokens: 1000,
+            stop: Vec::new(),
+            seed: None,
+            timeout_secs: None,
+            tools: Vec::new(),
+            json_mode: false,
+            constrain_tool_calls: false,
+            logprobs: None,
+            max_continuations: 0,
+            retry: RetryConfig::default(),
+            cache: CacheConfig::default(),
+            context: ContextConfig::default(),
+            concurrency: ConcurrencyConfig::default(),
+            fallbacks: Vec::new(),
+            embedding: EmbeddingConfig::default(),
+            prompt_template: PromptTemplate::default(),
+            ollama: OllamaOptions::default(),
+        }
+    }
+}
+
+impl LlmConfig {
+    pub fn from_value(value: &Value) -> anyhow::Result<Self> {
+        serde_json::from_value(value.clone())
+            .map_err(|e| anyhow::anyhow!("Invalid llm_config: {}", e))
+    }
+
+    /// Tokens available for the prompt once `max_tokens` are reserved for the
+    /// reply, `None` when the model's context window is unknown.
+    pub fn prompt_budget(&self) -> Option<usize> {
+        self.context
+            .context_limit
+            .or_else(|| default_context_limit(&self.model))
+            .map(|limit| limit.saturating_sub(self.max_tokens as usize))
+    }
+
+    /// Trims `messages` to `prompt_budget`, keeping the system message and the
+    /// most recent turns. Nothing is trimmed when the budget is unknown.
+    pub fn fit_to_context(&self, messages: Vec<Message>) -> (Vec<Message>, TruncationReport) {
+        match self.prompt_budget() {
+            Some(budget) => fit_to_context(
+                messages,
+                budget,
+                self.context.keep_recent,
+                &self.context.strategy,
+            ),
+            None => {
+                let report = TruncationReport {
+                    kept_tokens: messages.iter().map(estimate_message_tokens).sum(),
+                    ..Default::default()
+                };
+                (messages, report)
+            }
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        serde_json::to_value(self).expect("LlmConfig is always serializable")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn partial_configs_keep_the_defaults() {
+        let llm_config = LlmConfig::from_value(&json!({
+            "api_base": "https://api.openai.com/v1",
+            "model": "gpt-4o",
+            "api_key": { "env": "OPENAI_API_KEY" },
+            "retry": { "max_retries": 1 },
+        }))
+        .unwrap();
+
+        let defaults = LlmConfig::default();
+        assert_eq!(llm_config.model, "gpt-4o");
+        assert_eq!(
+            llm_config.api_key,
+            ApiKeySource::Env("OPENAI_API_KEY".to_string())
+        );
+        assert_eq!(llm_config.retry.max_retries, 1);
+        assert_eq!(
+            llm_config.retry.initial_backoff_ms,
+            defaults.retry.initial_backoff_ms
+        );
+        assert_eq!(llm_config.max_tokens, defaults.max_tokens);
+        assert_eq!(
+            LlmConfig::from_value(&llm_config.to_value()).unwrap(),
+            llm_config
+        );
+    }
+
+    #[test]
+    fn api_keys_are_externally_tagged() {
+        let keyless = LlmConfig::from_value(&json!({ "api_key": "none" })).unwrap();
+        assert_eq!(keyless.api_key, ApiKeySource::None);
+
+        let inline = LlmConfig::from_value(&json!({ "api_key": { "value": "sk-test" } })).unwrap();
+        assert_eq!(inline.api_key, ApiKeySource::Value("sk-test".to_string()));
+
+        assert!(LlmConfig::from_value(&json!({ "api_key": "sk-test" })).is_err());
+    }
+}
@@ -0,0 +1,337 @@
+use async_openai::error::OpenAIError;
+use serde::Deserialize;
+use serde_json::Value;
+use std::fmt;
+
+/// Why an LLM call failed, split by how the caller should react.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LlmError {
+    /// The server could not be reached or the connection dropped.
+    Transport(String),
+    /// No reply within the configured `timeout_secs`.
+    Timeout(u64),
+    /// HTTP 429, the server asked us to slow down.
+    RateLimited(String),
+    /// HTTP 5xx, including llama.cpp's "Loading model".
+    Server(String),
+    /// The prompt does not fit the model's context window.
+    ContextLength(String),
+    /// The server answered without any content.
+    EmptyOutput,
+    /// The response body could not be decoded.
+    Parse(String),
+    /// The endpoint won't serve the request, e.g. an unknown model (HTTP 404)
+    /// or a content filter. Another endpoint may.
+    Refused(String),
+    /// Any other rejection by the API, e.g. a malformed request.
+    Api(String),
+    /// Bad local configuration, such as a missing api key.
+    Config(String),
+    /// A replayed request has no matching recording in the cassette.
+    Replay(String),
+    /// The turn was cancelled through its `TurnControl`.
+    Cancelled,
+}
+
+impl LlmError {
+    /// Classifies a non-2xx reply by its HTTP status when the body says nothing better.
+    pub fn from_status(status: u16, message: String) -> LlmError {
+        if status == 429 {
+            LlmError::RateLimited(message)
+        } else if (500..600).contains(&status) {
+            LlmError::Server(message)
+        } else if status == 404 {
+            LlmError::Refused(message)
+        } else if message.contains("context") {
+            LlmError::ContextLength(message)
+        } else {
+            LlmError::Api(message)
+        }
+    }
+
+    /// Classifies a non-2xx reply from its status and body.
+    ///
+    /// Reads the `error` object of OpenAI-style bodies itself: llama.cpp sends
+    /// the HTTP status as an integer `code`, which async-openai's `ApiError`
+    /// can't decode. Other bodies fall back to `from_status`.
+    pub fn from_response(status: u16, body: &str) -> LlmError {
+        #[derive(Deserialize)]
+        struct ErrorObject {
+            message: String,
+            #[serde(rename = "type")]
+            kind: Option<String>,
+            code: Option<Value>,
+        }
+        #[derive(Deserialize)]
+        struct WrappedError {
+            error: ErrorObject,
+        }
+
+        match serde_json::from_str::<WrappedError>(body) {
+            Ok(WrappedError { error }) => {
+                let code = match error.code {
+                    Some(Value::String(code)) => code,
+                    Some(Value::Null) | None => String::new(),
+                    Some(code) => code.to_string(),
+                };
+                match api_error_variant(
+                    &code,
+                    error.kind.as_deref().unwrap_or_default(),
+                    &error.message,
+                ) {
+                    Some(variant) => variant(error.message),
+                    None => LlmError::from_status(status, error.message),
+                }
+            }
+            Err(_) => LlmError::from_status(status, body.to_string()),
+        }
+    }
+
+    /// Like `From<reqwest::Error>`, but reports timeouts of requests sent with
+    /// `timeout_secs` as `Timeout`.
+    pub fn from_reqwest(e: reqwest::Error, timeout_secs: Option<u64>) -> LlmError {
+        match timeout_secs {
+            Some(secs) if e.is_timeout() => LlmError::Timeout(secs),
+            _ => LlmError::from(e),
+        }
+    }
+
+    /// Whether the same request may succeed if sent again later.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            LlmError::Transport(_)
+                | LlmError::Timeout(_)
+                | LlmError::RateLimited(_)
+                | LlmError::Server(_)
+        )
+    }
+
+    /// Whether another endpoint may take the request this one failed. Bad
+    /// requests and auth failures (`Api`) would fail on every endpoint alike.
+    pub fn should_fall_back(&self) -> bool {
+        self.is_transient() || matches!(self, LlmError::ContextLength(_) | LlmError::Refused(_))
+    }
+}
+
+/// The variant for an API error's `code` and `type`, where OpenAI puts a slug
+/// and llama.cpp the HTTP status, or `None` when they don't tell.
+fn api_error_variant(code: &str, kind: &str, message: &str) -> Option<fn(String) -> LlmError> {
+    let message = message.to_lowercase();
+    if code == "context_length_exceeded"
+        || kind == "exceed_context_size_error"
+        || message.contains("context length")
+        || message.contains("context size")
+        || message.contains("maximum context")
+    {
+        Some(LlmError::ContextLength)
+    } else if code == "429" || code == "rate_limit_exceeded" || kind == "rate_limit_error" {
+        Some(LlmError::RateLimited)
+    } else if (code.len() == 3 && code.starts_with('5'))
+        || kind == "server_error"
+        || kind == "unavailable_error"
+    {
+        Some(LlmError::Server)
+    } else if code == "404"
+        || code == "model_not_found"
+        || code == "content_filter"
+        || kind == "not_found_error"
+    {
+        Some(LlmError::Refused)
+    } else {
+        None
+    }
+}
+
+impl fmt::Display for LlmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LlmError::Transport(msg) => write!(f, "transport error: {}", msg),
+            LlmError::Timeout(secs) => write!(f, "request timed out after {}s", secs),
+            LlmError::RateLimited(msg) => write!(f, "rate limited: {}", msg),
+            LlmError::Server(msg) => write!(f, "server error: {}", msg),
+            LlmError::ContextLength(msg) => write!(f, "context length exceeded: {}", msg),
+            LlmError::EmptyOutput => write!(f, "empty output in Llama format"),
+            LlmError::Parse(msg) => write!(f, "failed to parse response: {}", msg),
+            LlmError::Refused(msg) => write!(f, "refused: {}", msg),
+            LlmError::Api(msg) => write!(f, "api error: {}", msg),
+            LlmError::Config(msg) => write!(f, "invalid config: {}", msg),
+            LlmError::Replay(msg) => write!(f, "cassette mismatch: {}", msg),
+            LlmError::Cancelled => write!(f, "request cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for LlmError {}
+
+impl From<reqwest::Error> for LlmError {
+    fn from(e: reqwest::Error) -> LlmError {
+        match e.status() {
+            Some(status) if status.as_u16() == 429 => LlmError::RateLimited(e.to_string()),
+            Some(status) if status.is_server_error() => LlmError::Server(e.to_string()),
+            _ => LlmError::Transport(e.to_string()),
+        }
+    }
+}
+
+impl From<OpenAIError> for LlmError {
+    fn from(error: OpenAIError) -> LlmError {
+        match error {
+            OpenAIError::Reqwest(e) => LlmError::from(e),
+            OpenAIError::ApiError(api) => match api_error_variant(
+                api.code.as_deref().unwrap_or_default(),
+                api.r#type.as_deref().unwrap_or_default(),
+                &api.message,
+            ) {
+                Some(variant) => variant(api.message),
+                None => LlmError::Api(api.message),
+            },
+            OpenAIError::JSONDeserialize(e) => LlmError::Parse(e.to_string()),
+            // a non-2xx reply to a streamed request, without its body
+            OpenAIError::StreamError(msg) => match msg
+                .strip_prefix("Invalid status code: ")
+                .and_then(|status| status.split_whitespace().next())
+                .and_then(|status| status.parse().ok())
+            {
+                Some(status) => LlmError::from_status(status, msg),
+                None => LlmError::Transport(msg),
+            },
+            OpenAIError::InvalidArgument(msg) => LlmError::Config(msg),
+            other => LlmError::Api(other.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn classify(status: u16, body: Value) -> LlmError {
+        LlmError::from_response(status, &body.to_string())
+    }
+
+    #[test]
+    fn rate_limits_are_transient() {
+        let openai = classify(
+            429,
+            json!({ "error": {
+                "message": "Rate limit reached for requests",
+                "type": "requests",
+                "param": null,
+                "code": "rate_limit_exceeded",
+            }}),
+        );
+        assert!(matches!(openai, LlmError::RateLimited(_)));
+        assert!(openai.is_transient());
+
+        let llama = classify(
+            429,
+            json!({ "error": {
+                "code": 429, "message": "too many requests", "type": "rate_limit_error",
+            }}),
+        );
+        assert!(matches!(llama, LlmError::RateLimited(_)));
+        assert!(matches!(
+            LlmError::from_status(429, "slow down".to_string()),
+            LlmError::RateLimited(_)
+        ));
+    }
+
+    #[test]
+    fn loading_model_is_a_transient_server_error() {
+        let error = classify(
+            503,
+            json!({ "error": {
+                "code": 503, "message": "Loading model", "type": "unavailable_error",
+            }}),
+        );
+        assert!(matches!(error, LlmError::Server(_)));
+        assert!(error.is_transient());
+        assert!(matches!(
+            LlmError::from_status(503, "Service Unavailable".to_string()),
+            LlmError::Server(_)
+        ));
+    }
+
+    #[test]
+    fn context_overflows_are_permanent() {
+        let llama = classify(
+            400,
+            json!({ "error": {
+                "code": 400,
+                "message": "the request exceeds the available context size, try increasing it",
+                "type": "exceed_context_size_error",
+            }}),
+        );
+        assert!(matches!(llama, LlmError::ContextLength(_)));
+        assert!(!llama.is_transient());
+
+        let openai = classify(
+            400,
+            json!({ "error": {
+                "message": "This model's maximum context length is 8192 tokens.",
+                "type": "invalid_request_error",
+                "param": "messages",
+                "code": "context_length_exceeded",
+            }}),
+        );
+        assert!(matches!(openai, LlmError::ContextLength(_)));
+    }
+
+    #[test]
+    fn other_rejections_are_api_errors() {
+        let error = classify(
+            400,
+            json!({ "error": {
+                "message": "'messages' must contain at least one message",
+                "type": "invalid_request_error",
+                "param": null,
+                "code": null,
+            }}),
+        );
+        assert!(matches!(error, LlmError::Api(_)));
+        assert!(!error.is_transient());
+        assert!(!error.should_fall_back());
+
+        let unauthorized = LlmError::from_status(401, "Invalid API key".to_string());
+        assert!(matches!(unauthorized, LlmError::Api(_)));
+        assert!(!unauthorized.should_fall_back());
+    }
+
+    #[test]
+    fn unknown_models_fall_back() {
+        let openai = classify(
+            404,
+            json!({ "error": {
+                "message": "The model `gpt-5` does not exist",
+                "type": "invalid_request_error",
+                "param": null,
+                "code": "model_not_found",
+            }}),
+        );
+        assert!(matches!(openai, LlmError::Refused(_)));
+        assert!(openai.should_fall_back());
+        assert!(!openai.is_transient());
+
+        let ollama = LlmError::from_status(404, "model \"llama3\" not found".to_string());
+        assert!(matches!(ollama, LlmError::Refused(_)));
+    }
+
+    #[test]
+    fn bodies_without_an_error_object_are_classified_by_status() {
+        assert!(matches!(
+            LlmError::from_response(503, "Service Unavailable"),
+            LlmError::Server(_)
+        ));
+        assert!(matches!(
+            LlmError::from_response(400, "{\"detail\": \"bad\"}"),
+            LlmError::Api(_)
+        ));
+
+        let streamed = LlmError::from(OpenAIError::StreamError(
+            "Invalid status code: 429 Too Many Requests".to_string(),
+        ));
+        assert!(matches!(streamed, LlmError::RateLimited(_)));
+    }
+}
@@ -1,28 +1,70 @@
+use crate::context_budget::{estimate_message_tokens, estimate_tokens};
 use crate::conversable_agent::Message;
-use crate::llama_structs::{output_llama_response, Content, LlamaResponseMessage};
+use crate::llama_structs::{
+    hermes_tool_response, output_llama_response, parse_llama_content, tool_call_from_json,
+    tool_output_text, Content, ContentPart, LlamaResponseMessage, TokenLogprob, ToolCall,
+    ToolDefinition,
+};
+use crate::llm_config::LlmConfig;
+use crate::llm_error::LlmError;
+use crate::request_limiter::acquire_permit;
+use crate::response_cache::chat_with_cache;
 use async_openai::{
     config::Config,
     types::{
+        ChatCompletionMessageToolCall,
         ChatCompletionRequestAssistantMessage,
         ChatCompletionRequestMessage,
+        ChatCompletionRequestMessageContentPart,
+        ChatCompletionRequestMessageContentPartImageArgs,
+        ChatCompletionRequestMessageContentPartTextArgs,
         // ChatCompletionFunctionsArgs,
         ChatCompletionRequestSystemMessage,
         ChatCompletionRequestSystemMessageArgs,
+        ChatCompletionRequestToolMessage,
         ChatCompletionRequestUserMessage,
         ChatCompletionRequestUserMessageArgs,
         ChatCompletionRequestUserMessageContent,
-        // ChatCompletionTool, ChatCompletionToolArgs, ChatCompletionToolType,
+        ChatCompletionResponseFormat,
+        ChatCompletionResponseFormatType,
+        ChatCompletionTool,
+        ChatCompletionToolType,
+        CompletionFinishReason,
+        CompletionUsage,
+        CreateChatCompletionRequest,
         CreateChatCompletionRequestArgs,
         CreateChatCompletionResponse,
+        CreateChatCompletionStreamResponse,
+        CreateCompletionRequestArgs,
+        CreateCompletionResponse,
+        CreateEmbeddingRequestArgs,
+        CreateEmbeddingResponse,
+        FinishReason,
+        FunctionCall,
+        FunctionObject,
+        ImageUrlArgs,
         Role,
+        Stop,
     },
     Client as OpenAIClient,
 };
-use dotenv;
+use async_trait::async_trait;
+use backoff::ExponentialBackoffBuilder;
+use futures::channel::mpsc;
+use futures::{Stream, StreamExt};
 use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE, USER_AGENT};
-use secrecy::Secret;
-use serde::Deserialize;
+use reqwest::RequestBuilder;
+use reqwest_eventsource::{Error as EventSourceError, Event, EventSource};
+use secrecy::{ExposeSecret, Secret};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
 
 #[derive(Clone, Debug)]
 pub struct LocalServiceProviderConfig {
@@ -57,25 +99,31 @@ impl Config for LocalServiceProviderConfig {
     }
 }
 
+impl LocalServiceProviderConfig {
+    pub fn from_llm_config(llm_config: &LlmConfig) -> Result<Self, LlmError> {
+        let mut headers = HeaderMap::new();
+        let api_key = llm_config
+            .api_key
+            .resolve()
+            .map_err(|e| LlmError::Config(e.to_string()))?;
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        headers.insert(USER_AGENT, HeaderValue::from_static("MyClient/1.0.0"));
+        Ok(LocalServiceProviderConfig {
+            api_base: llm_config.api_base.clone(),
+            headers,
+            api_key: Secret::new(api_key),
+            query: HashMap::new(),
+        })
+    }
+}
+
 pub async fn chat_inner_async(
     system_prompt: &str,
     user_input: &str,
     max_token: u16,
 ) -> anyhow::Result<CreateChatCompletionResponse> {
-    let mut headers = HeaderMap::new();
-    let api_key = std::env::var("LLAMA_API_KEY").expect("LLAMA_API_KEY must be set");
-    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
-    headers.insert(USER_AGENT, HeaderValue::from_static("MyClient/1.0.0"));
-    let config = LocalServiceProviderConfig {
-        api_base: String::from("http://127.0.0.1:8080/v1"),
-        headers: headers,
-        api_key: Secret::new(api_key),
-        query: HashMap::new(),
-    };
-
-    // stop: ['</s>', '[/INST]'],
-    let model = "Hermes-2-Pro-Llama-3-8B";
-    let client = OpenAIClient::with_config(config);
+    let llm_config = LlmConfig::default();
+    let client = LlamaLocalClient::shared().openai_client(&llm_config)?;
     let messages = vec![
         ChatCompletionRequestSystemMessageArgs::default()
             .content(system_prompt)
@@ -89,7 +137,7 @@ pub async fn chat_inner_async(
     ];
     let request = CreateChatCompletionRequestArgs::default()
         .max_tokens(max_token)
-        .model(model)
+        .model(&llm_config.model)
         .messages(messages)
         .build()?;
 
@@ -108,24 +156,111 @@ impl Message {
     pub fn content_to_string(&self) -> Option<String> {
         match &self.content {
             Some(Content::Text(text)) => Some(text.clone()),
-            Some(Content::ToolCall(tool_call)) => Some(format!(
-                "tool_call: {}, arguments: {}",
-                tool_call.name,
-                tool_call
-                    .arguments.as_ref()
-                    .unwrap()
-                    .into_iter()
-                    .map(|(arg, val)| format!("{:?}: {:?}", arg, val))
+            Some(Content::ToolCalls { text, calls }) => Some(
+                text.iter()
+                    .cloned()
+                    .chain(calls.iter().map(|tool_call| match &tool_call.source_text {
+                        Some(source) => source.clone(),
+                        None => format!(
+                            "tool_call: {}, arguments: {}",
+                            tool_call.name,
+                            tool_call.arguments_json()
+                        ),
+                    }))
                     .collect::<Vec<String>>()
-                    .join(", ")
-            )),
+                    .join("\n"),
+            ),
+            Some(Content::Parts(parts)) => Some(
+                parts
+                    .iter()
+                    .map(|part| match part {
+                        ContentPart::Text(text) => text.as_str(),
+                        ContentPart::ImageUrl(_) => "[image]",
+                    })
+                    .collect::<Vec<&str>>()
+                    .join("\n"),
+            ),
+            // text-only paths see the result the way Hermes models are trained on
+            Some(Content::ToolResult {
+                name,
+                output,
+                is_error,
+                ..
+            }) => Some(hermes_tool_response(name, output, *is_error)),
             None => None,
         }
     }
 }
 
+impl From<&ContentPart> for ChatCompletionRequestMessageContentPart {
+    fn from(part: &ContentPart) -> ChatCompletionRequestMessageContentPart {
+        match part {
+            ContentPart::Text(text) => ChatCompletionRequestMessageContentPartTextArgs::default()
+                .text(text.clone())
+                .build()
+                .expect("text part has every field set")
+                .into(),
+            ContentPart::ImageUrl(url) => {
+                ChatCompletionRequestMessageContentPartImageArgs::default()
+                    .image_url(
+                        ImageUrlArgs::default()
+                            .url(url.clone())
+                            .build()
+                            .expect("image url has every field set"),
+                    )
+                    .build()
+                    .expect("image part has every field set")
+                    .into()
+            }
+        }
+    }
+}
+
+impl From<ToolCall> for ChatCompletionMessageToolCall {
+    fn from(tool_call: ToolCall) -> ChatCompletionMessageToolCall {
+        let arguments = tool_call.arguments_json();
+        ChatCompletionMessageToolCall {
+            id: tool_call.id,
+            r#type: ChatCompletionToolType::Function,
+            function: FunctionCall {
+                name: tool_call.name,
+                arguments,
+            },
+        }
+    }
+}
+
+impl From<&ToolDefinition> for ChatCompletionTool {
+    fn from(tool: &ToolDefinition) -> ChatCompletionTool {
+        ChatCompletionTool {
+            r#type: ChatCompletionToolType::Function,
+            function: FunctionObject {
+                name: tool.name.clone(),
+                description: tool.description.clone(),
+                parameters: Some(tool.parameters.clone()),
+            },
+        }
+    }
+}
+
+// `function_call` is deprecated but still a required field of the struct
+#[allow(deprecated)]
 impl From<Message> for ChatCompletionRequestMessage {
     fn from(message: Message) -> ChatCompletionRequestMessage {
+        if let Some(Content::ToolResult {
+            call_id,
+            output,
+            is_error,
+            ..
+        }) = &message.content
+        {
+            return ChatCompletionRequestMessage::Tool(ChatCompletionRequestToolMessage {
+                content: tool_output_text(output, *is_error),
+                role: Role::Tool,
+                tool_call_id: call_id.clone(),
+            });
+        }
+
         match message.role {
             Some(Role::System) => {
                 ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
@@ -135,21 +270,68 @@ impl From<Message> for ChatCompletionRequestMessage {
                 })
             }
             Some(Role::User) => {
-                ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
-                    content: ChatCompletionRequestUserMessageContent::Text(
+                let content = match &message.content {
+                    // vision models take text and images as an array of parts
+                    Some(Content::Parts(parts)) => ChatCompletionRequestUserMessageContent::Array(
+                        parts
+                            .iter()
+                            .map(ChatCompletionRequestMessageContentPart::from)
+                            .collect(),
+                    ),
+                    _ => ChatCompletionRequestUserMessageContent::Text(
                         message.content_to_string().unwrap_or("empty".to_string()),
                     ),
+                };
+                ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+                    content,
                     role: Role::User,
                     name: message.name,
                 })
             }
-            Some(Role::Assistant) => {
-                ChatCompletionRequestMessage::Assistant(ChatCompletionRequestAssistantMessage {
-                    content: Some(message.content_to_string().unwrap_or("empty".to_string())),
-                    role: Role::Assistant,
+            Some(Role::Assistant) => match &message.content {
+                // native calls are echoed back as `tool_calls` so results can refer
+                // to their ids, calls read from the reply text go back as that text
+                Some(Content::ToolCalls { text, calls }) => {
+                    let (native, written): (Vec<&ToolCall>, Vec<&ToolCall>) =
+                        calls.iter().partition(|call| call.source_text.is_none());
+                    let content = text
+                        .iter()
+                        .chain(written.iter().filter_map(|call| call.source_text.as_ref()))
+                        .cloned()
+                        .collect::<Vec<String>>()
+                        .join("\n");
+                    ChatCompletionRequestMessage::Assistant(ChatCompletionRequestAssistantMessage {
+                        content: (!content.is_empty()).then_some(content),
+                        role: Role::Assistant,
+                        name: message.name,
+                        tool_calls: (!native.is_empty()).then(|| {
+                            native
+                                .into_iter()
+                                .cloned()
+                                .map(ChatCompletionMessageToolCall::from)
+                                .collect()
+                        }),
+                        function_call: None,
+                    })
+                }
+                _ => {
+                    ChatCompletionRequestMessage::Assistant(ChatCompletionRequestAssistantMessage {
+                        content: Some(message.content_to_string().unwrap_or("empty".to_string())),
+                        role: Role::Assistant,
+                        name: message.name,
+                        tool_calls: None,
+                        function_call: None,
+                    })
+                }
+            },
+            // plain text in a tool turn has no call to match, hand it back as user text
+            Some(Role::Tool) => {
+                ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+                    content: ChatCompletionRequestUserMessageContent::Text(
+                        message.content_to_string().unwrap_or("empty".to_string()),
+                    ),
+                    role: Role::User,
                     name: message.name,
-                    tool_calls: None,
-                    function_call: None,
                 })
             }
             Some(_) => {
@@ -167,51 +349,767 @@ impl From<Message> for ChatCompletionRequestMessage {
     }
 }
 
-pub async fn chat_inner_async_llama(
-    messages: Vec<Message>,
-    max_token: u16,
-) -> anyhow::Result<LlamaResponseMessage> {
-    let mut headers = HeaderMap::new();
-    let api_key = std::env::var("LLAMA_API_KEY").expect("LLAMA_API_KEY must be set");
-    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
-    headers.insert(USER_AGENT, HeaderValue::from_static("MyClient/1.0.0"));
-    let config = LocalServiceProviderConfig {
-        api_base: String::from("http://127.0.0.1:8080/v1"),
-        headers: headers,
-        api_key: Secret::new(api_key),
-        query: HashMap::new(),
-    };
+/// A chat backend that turns a conversation into the next reply.
+///
+/// `ConversableAgent` holds one of these, so each agent can talk to its own
+/// server and tests can plug in a canned implementation.
+#[async_trait]
+pub trait LlmClient: Send + Sync {
+    async fn chat(
+        &self,
+        messages: Vec<Message>,
+        llm_config: &LlmConfig,
+    ) -> Result<LlamaResponseMessage, LlmError>;
+
+    /// Streams the reply as it is generated. The default implementation waits
+    /// for `chat` and emits the whole text as a single delta.
+    ///
+    /// Nothing retries or continues a stream the way `chat_with_retry` and
+    /// `chat_with_continuation` do for `chat`: the caller sees a failure or a
+    /// cut-off reply as is.
+    async fn chat_stream(
+        &self,
+        messages: Vec<Message>,
+        llm_config: &LlmConfig,
+    ) -> Result<LlmStream, LlmError> {
+        let reply = self.chat(messages, llm_config).await?;
+        Ok(reply_as_stream(reply))
+    }
+
+    /// Embeds each input, batched by `llm_config.embedding.batch_size`.
+    /// The default implementation reports embeddings as unsupported.
+    async fn embed(
+        &self,
+        inputs: Vec<String>,
+        llm_config: &LlmConfig,
+    ) -> Result<Vec<Vec<f32>>, LlmError> {
+        let _ = (inputs, llm_config);
+        Err(LlmError::Config(
+            "this client does not support embeddings".to_string(),
+        ))
+    }
+}
 
-    // stop: ['</s>', '[/INST]'],
-    let model = "Hermes-2-Pro-Llama-3-8B";
-    let client = OpenAIClient::with_config(config);
+/// A finished reply as a stream: its text in one delta, then `Done`.
+pub fn reply_as_stream(reply: LlamaResponseMessage) -> LlmStream {
+    let mut chunks = Vec::new();
+    if let Content::Text(text) = &reply.content {
+        chunks.push(Ok(StreamChunk::Delta(text.clone())));
+    }
+    chunks.push(Ok(StreamChunk::Done(reply)));
+    Box::pin(futures::stream::iter(chunks))
+}
+
+/// One item of a streamed reply.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamChunk {
+    /// Text generated since the previous chunk.
+    Delta(String),
+    /// The assembled reply, always the last item of a successful stream.
+    Done(LlamaResponseMessage),
+}
 
+pub type LlmStream = Pin<Box<dyn Stream<Item = Result<StreamChunk, LlmError>> + Send>>;
+
+/// Client for the raw `/completions` endpoint, rendering prompts itself.
+#[derive(Clone, Debug, Default)]
+pub struct LlamaCompletionClient {
+    client: LlamaLocalClient,
+}
+
+impl LlamaCompletionClient {
+    pub fn new(client: LlamaLocalClient) -> Self {
+        LlamaCompletionClient { client }
+    }
+}
+
+#[async_trait]
+impl LlmClient for LlamaCompletionClient {
+    async fn chat(
+        &self,
+        messages: Vec<Message>,
+        llm_config: &LlmConfig,
+    ) -> Result<LlamaResponseMessage, LlmError> {
+        self.client.complete(messages, llm_config).await
+    }
+}
+
+type LocalOpenAIClient = OpenAIClient<LocalServiceProviderConfig>;
+
+/// Client for a local OpenAI-compatible server such as llama.cpp.
+///
+/// Holds one pooled HTTP client, and builds the async-openai client of each
+/// endpoint and key once, so connections are reused across requests. A key
+/// read from the environment is looked up once, when that client is built.
+/// Clones share both.
+#[derive(Clone)]
+pub struct LlamaLocalClient {
+    http: reqwest::Client,
+    /// Keyed by `api_base` and a hash of the key's source, the key itself is
+    /// never kept here.
+    clients: Arc<Mutex<HashMap<String, LocalOpenAIClient>>>,
+}
+
+// the pooled clients hold API keys, leave them out
+impl fmt::Debug for LlamaLocalClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LlamaLocalClient")
+            .field("http", &self.http)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for LlamaLocalClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LlamaLocalClient {
+    pub fn new() -> Self {
+        Self::with_http_client(reqwest::Client::new())
+    }
+
+    pub fn with_http_client(http: reqwest::Client) -> Self {
+        LlamaLocalClient {
+            http,
+            clients: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The client behind the free `chat_inner_async*` functions.
+    pub fn shared() -> &'static LlamaLocalClient {
+        static SHARED: OnceLock<LlamaLocalClient> = OnceLock::new();
+        SHARED.get_or_init(LlamaLocalClient::new)
+    }
+
+    /// The async-openai client for `llm_config`'s endpoint and key, built on first use.
+    pub fn openai_client(&self, llm_config: &LlmConfig) -> Result<LocalOpenAIClient, LlmError> {
+        let source =
+            serde_json::to_vec(&llm_config.api_key).map_err(|e| LlmError::Config(e.to_string()))?;
+        let key = format!("{}\n{:x}", llm_config.api_base, Sha256::digest(source));
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(client) = clients.get(&key) {
+            return Ok(client.clone());
+        }
+
+        let config = LocalServiceProviderConfig::from_llm_config(llm_config)?;
+        // async-openai retries 429s on its own, leave `llm_config.retry` as the only policy
+        let no_retries = ExponentialBackoffBuilder::new()
+            .with_max_elapsed_time(Some(Duration::ZERO))
+            .build();
+        let client = OpenAIClient::with_config(config)
+            .with_http_client(self.http.clone())
+            .with_backoff(no_retries);
+        clients.insert(key, client.clone());
+        Ok(client)
+    }
+
+    /// Sends `messages` to the plain text-completion endpoint, rendered with
+    /// `llm_config.prompt_template`, for servers that don't apply a chat template.
+    pub async fn complete(
+        &self,
+        messages: Vec<Message>,
+        llm_config: &LlmConfig,
+    ) -> Result<LlamaResponseMessage, LlmError> {
+        let client = self.openai_client(llm_config)?;
+
+        let template = llm_config.prompt_template;
+        let prompt = template.render(&messages);
+        let mut stop = template.stop_sequences();
+        stop.extend(llm_config.stop.iter().cloned());
+
+        let mut args = CreateCompletionRequestArgs::default();
+        args.model(&llm_config.model)
+            .prompt(prompt)
+            .max_tokens(llm_config.max_tokens)
+            .stop(Stop::StringArray(stop));
+        if let Some(temperature) = llm_config.temperature {
+            args.temperature(temperature);
+        }
+        if let Some(top_p) = llm_config.top_p {
+            args.top_p(top_p);
+        }
+        if let Some(seed) = llm_config.seed {
+            args.seed(seed);
+        }
+        let request = args.build()?;
+
+        let res: CreateCompletionResponse = post_json(
+            &self.http,
+            client.config(),
+            "/completions",
+            &request,
+            llm_config.timeout_secs,
+        )
+        .await?;
+        let choice = res
+            .choices
+            .into_iter()
+            .next()
+            .ok_or(LlmError::EmptyOutput)?;
+        let text = choice.text;
+
+        let usage = res.usage.unwrap_or_else(|| {
+            let prompt_tokens = messages.iter().map(estimate_message_tokens).sum::<usize>() as u32;
+            let completion_tokens = estimate_tokens(&text) as u32;
+            CompletionUsage {
+                prompt_tokens,
+                completion_tokens,
+                total_tokens: prompt_tokens + completion_tokens,
+            }
+        });
+
+        let mut response =
+            LlamaResponseMessage::new(parse_llama_content(text.trim()), Role::Assistant, usage);
+        response.finish_reason = choice.finish_reason.map(|reason| match reason {
+            CompletionFinishReason::Stop => FinishReason::Stop,
+            CompletionFinishReason::Length => FinishReason::Length,
+            CompletionFinishReason::ContentFilter => FinishReason::ContentFilter,
+        });
+        Ok(response)
+    }
+}
+
+#[async_trait]
+impl LlmClient for LlamaLocalClient {
+    async fn chat(
+        &self,
+        messages: Vec<Message>,
+        llm_config: &LlmConfig,
+    ) -> Result<LlamaResponseMessage, LlmError> {
+        let client = self.openai_client(llm_config)?;
+
+        if llm_config.constrain_tool_calls && !llm_config.tools.is_empty() {
+            return chat_constrained_tool_call(&self.http, client.config(), messages, llm_config)
+                .await;
+        }
+
+        let request = build_chat_request(messages, llm_config)?;
+
+        let chat: CreateChatCompletionResponse = post_json(
+            &self.http,
+            client.config(),
+            "/chat/completions",
+            &request,
+            llm_config.timeout_secs,
+        )
+        .await?;
+
+        output_llama_response(chat).ok_or(LlmError::EmptyOutput)
+    }
+
+    async fn chat_stream(
+        &self,
+        messages: Vec<Message>,
+        llm_config: &LlmConfig,
+    ) -> Result<LlmStream, LlmError> {
+        let client = self.openai_client(llm_config)?;
+
+        // llama.cpp can't stream a schema-constrained reply, a call is short anyway
+        if llm_config.constrain_tool_calls && !llm_config.tools.is_empty() {
+            let reply =
+                chat_constrained_tool_call(&self.http, client.config(), messages, llm_config)
+                    .await?;
+            return Ok(reply_as_stream(reply));
+        }
+
+        let prompt_tokens = messages.iter().map(estimate_message_tokens).sum::<usize>() as u32;
+        let mut request = build_chat_request(messages, llm_config)?;
+        request.stream = Some(true);
+
+        let builder = post_request(&self.http, client.config(), "/chat/completions", &request)?;
+        let mut events = EventSource::new(builder).map_err(|e| LlmError::Config(e.to_string()))?;
+        // wait for the server to take the request, so a refusal fails here
+        // with the reason from its body rather than as the first stream item
+        match events.next().await {
+            Some(Ok(_)) => {}
+            Some(Err(e)) => {
+                return Err(event_source_error(e).await);
+            }
+            None => return Err(LlmError::EmptyOutput),
+        }
+
+        let (tx, rx) = mpsc::unbounded();
+        tokio::spawn(async move {
+            let mut text = String::new();
+            let mut role = Role::Assistant;
+            let mut completion_tokens = 0u32;
+            let mut finish_reason = None;
+            let mut logprobs: Option<Vec<TokenLogprob>> = None;
+            // native calls arrive in pieces, keyed by their index in the reply
+            let mut tool_calls: Vec<(i32, ChatCompletionMessageToolCall)> = Vec::new();
+
+            while let Some(event) = events.next().await {
+                let data = match event {
+                    Ok(Event::Open) => continue,
+                    Ok(Event::Message(message)) if message.data == "[DONE]" => break,
+                    Ok(Event::Message(message)) => message.data,
+                    Err(e) => {
+                        let _ = tx.unbounded_send(Err(event_source_error(e).await));
+                        return;
+                    }
+                };
+                let response: CreateChatCompletionStreamResponse = match serde_json::from_str(&data)
+                {
+                    Ok(response) => response,
+                    Err(e) => {
+                        let _ = tx.unbounded_send(Err(LlmError::Parse(e.to_string())));
+                        return;
+                    }
+                };
+                if let Some(choice) = response.choices.into_iter().next() {
+                    if let Some(delta_role) = choice.delta.role {
+                        role = delta_role;
+                    }
+                    if choice.finish_reason.is_some() {
+                        finish_reason = choice.finish_reason;
+                    }
+                    if let Some(tokens) = choice.logprobs.and_then(|l| l.content) {
+                        logprobs
+                            .get_or_insert_with(Vec::new)
+                            .extend(tokens.into_iter().map(TokenLogprob::from));
+                    }
+                    for chunk in choice.delta.tool_calls.unwrap_or_default() {
+                        completion_tokens += 1;
+                        let at = match tool_calls.iter().position(|(i, _)| *i == chunk.index) {
+                            Some(at) => at,
+                            None => {
+                                let call = ChatCompletionMessageToolCall {
+                                    id: String::new(),
+                                    r#type: ChatCompletionToolType::Function,
+                                    function: FunctionCall {
+                                        name: String::new(),
+                                        arguments: String::new(),
+                                    },
+                                };
+                                tool_calls.push((chunk.index, call));
+                                tool_calls.len() - 1
+                            }
+                        };
+                        let call = &mut tool_calls[at].1;
+                        if let Some(id) = chunk.id {
+                            call.id.push_str(&id);
+                        }
+                        if let Some(function) = chunk.function {
+                            call.function
+                                .name
+                                .push_str(&function.name.unwrap_or_default());
+                            call.function
+                                .arguments
+                                .push_str(&function.arguments.unwrap_or_default());
+                        }
+                    }
+                    if let Some(delta) = choice.delta.content {
+                        completion_tokens += 1;
+                        text.push_str(&delta);
+                        if tx.unbounded_send(Ok(StreamChunk::Delta(delta))).is_err() {
+                            // receiver dropped, stop reading
+                            return;
+                        }
+                    }
+                }
+            }
+
+            let content = if tool_calls.is_empty() {
+                parse_llama_content(&text)
+            } else {
+                Content::ToolCalls {
+                    text: (!text.trim().is_empty()).then(|| text.trim().to_string()),
+                    calls: tool_calls
+                        .into_iter()
+                        .map(|(_, call)| ToolCall::from(call))
+                        .collect(),
+                }
+            };
+            let mut response = LlamaResponseMessage::new(
+                content,
+                role,
+                CompletionUsage {
+                    prompt_tokens,
+                    completion_tokens,
+                    total_tokens: prompt_tokens + completion_tokens,
+                },
+            );
+            response.finish_reason = finish_reason;
+            response.logprobs = logprobs;
+            let _ = tx.unbounded_send(Ok(StreamChunk::Done(response)));
+        });
+
+        Ok(Box::pin(rx))
+    }
+
+    async fn embed(
+        &self,
+        inputs: Vec<String>,
+        llm_config: &LlmConfig,
+    ) -> Result<Vec<Vec<f32>>, LlmError> {
+        let client = self.openai_client(llm_config)?;
+
+        let mut vectors = Vec::with_capacity(inputs.len());
+        for batch in inputs.chunks(llm_config.embedding.batch_size.max(1)) {
+            let request = CreateEmbeddingRequestArgs::default()
+                .model(llm_config.embedding_model())
+                .input(batch.to_vec())
+                .build()?;
+            let mut res: CreateEmbeddingResponse = post_json(
+                &self.http,
+                client.config(),
+                "/embeddings",
+                &request,
+                llm_config.timeout_secs,
+            )
+            .await?;
+            if res.data.len() != batch.len() {
+                return Err(LlmError::Parse(format!(
+                    "expected {} embeddings, got {}",
+                    batch.len(),
+                    res.data.len()
+                )));
+            }
+            res.data.sort_by_key(|embedding| embedding.index);
+            vectors.extend(res.data.into_iter().map(|embedding| embedding.embedding));
+        }
+        Ok(vectors)
+    }
+}
+
+pub fn build_chat_request(
+    messages: Vec<Message>,
+    llm_config: &LlmConfig,
+) -> Result<CreateChatCompletionRequest, LlmError> {
     let messages: Vec<ChatCompletionRequestMessage> = messages
         .into_iter()
         .map(ChatCompletionRequestMessage::from)
         .collect();
 
-    let request = CreateChatCompletionRequestArgs::default()
-        .max_tokens(max_token)
-        .model(model)
-        .messages(messages)
-        .build()?;
+    let mut args = CreateChatCompletionRequestArgs::default();
+    args.max_tokens(llm_config.max_tokens)
+        .model(&llm_config.model)
+        .messages(messages);
+    if let Some(temperature) = llm_config.temperature {
+        args.temperature(temperature);
+    }
+    if let Some(top_p) = llm_config.top_p {
+        args.top_p(top_p);
+    }
+    if !llm_config.stop.is_empty() {
+        args.stop(Stop::StringArray(llm_config.stop.clone()));
+    }
+    if let Some(seed) = llm_config.seed {
+        args.seed(seed);
+    }
+    if let Some(top_logprobs) = llm_config.logprobs {
+        args.logprobs(true).top_logprobs(top_logprobs);
+    }
+    if llm_config.json_mode {
+        args.response_format(ChatCompletionResponseFormat {
+            r#type: ChatCompletionResponseFormatType::JsonObject,
+        });
+    }
+    if !llm_config.tools.is_empty() {
+        args.tools(
+            llm_config
+                .tools
+                .iter()
+                .map(ChatCompletionTool::from)
+                .collect::<Vec<ChatCompletionTool>>(),
+        );
+    }
 
-    match client.chat().create(request).await {
-        Ok(chat) => {
-            if let Some(out) = output_llama_response(chat) {
-                Ok(out)
-            } else {
-                Err(anyhow::anyhow!("Empty output in Llama format"))
+    Ok(args.build()?)
+}
+
+pub async fn chat_inner_async_llama(
+    messages: Vec<Message>,
+    llm_config: &LlmConfig,
+) -> Result<LlamaResponseMessage, LlmError> {
+    LlamaLocalClient::shared().chat(messages, llm_config).await
+}
+
+/// JSON schema matching a call to any of `tools`, as `{"name": ..., "arguments": {...}}`.
+pub fn tool_call_schema(tools: &[ToolDefinition]) -> Value {
+    let variants: Vec<Value> = tools
+        .iter()
+        .map(|tool| {
+            json!({
+                "type": "object",
+                "properties": {
+                    "name": { "const": tool.name },
+                    "arguments": tool.parameters,
+                },
+                "required": ["name", "arguments"],
+            })
+        })
+        .collect();
+    json!({ "oneOf": variants })
+}
+
+/// Hermes-style system prompt listing `tools`, for requests that can't carry
+/// them natively.
+pub fn tools_system_prompt(tools: &[ToolDefinition]) -> String {
+    let signatures: Vec<String> = tools
+        .iter()
+        .map(|tool| json!(ChatCompletionTool::from(tool)).to_string())
+        .collect();
+    format!(
+        "You are a function calling AI model. You are provided with function signatures \
+         within <tools></tools> XML tags. Call one of them to assist with the user query. \
+         Don't make assumptions about what values to plug into functions. \
+         Here are the available tools: <tools> {} </tools> \
+         Reply with a JSON object of the form {{\"name\": <function-name>, \"arguments\": <args-dict>}}.",
+        signatures.join(" ")
+    )
+}
+
+/// Adds `tools_system_prompt` to the system message, or puts it first when
+/// there is none. A system prompt that already lists tools is left alone.
+fn with_tools_prompt(mut messages: Vec<Message>, tools: &[ToolDefinition]) -> Vec<Message> {
+    let prompt = tools_system_prompt(tools);
+    match messages.first_mut() {
+        Some(Message {
+            role: Some(Role::System),
+            content: Some(Content::Text(text)),
+            ..
+        }) => {
+            if !text.contains("<tools>") {
+                text.push_str("\n\n");
+                text.push_str(&prompt);
             }
         }
-        Err(_e) => {
-            println!("Error getting response from OpenAI: {:?}", _e);
-            Err(anyhow::anyhow!("Failed to get reply from OpenAI: {:?}", _e))
+        _ => messages.insert(
+            0,
+            Message::new(Some(Content::Text(prompt)), None, Some(Role::System), None),
+        ),
+    }
+    messages
+}
+
+/// Asks llama.cpp for a reply constrained by `tool_call_schema`, so the model can
+/// only answer with a well-formed call to one of `llm_config.tools`.
+///
+/// async-openai has no field for llama.cpp's `json_schema`, so the request is
+/// posted directly. llama.cpp refuses `tools` next to a schema, so the tools
+/// are described in the system prompt instead, as for Hermes-style calls.
+async fn chat_constrained_tool_call(
+    http: &reqwest::Client,
+    config: &LocalServiceProviderConfig,
+    messages: Vec<Message>,
+    llm_config: &LlmConfig,
+) -> Result<LlamaResponseMessage, LlmError> {
+    let messages = with_tools_prompt(messages, &llm_config.tools);
+    let mut request = build_chat_request(messages, llm_config)?;
+    request.tools = None;
+    let mut body = serde_json::to_value(&request).map_err(|e| LlmError::Parse(e.to_string()))?;
+    body["json_schema"] = tool_call_schema(&llm_config.tools);
+
+    let chat: CreateChatCompletionResponse = post_json(
+        http,
+        config,
+        "/chat/completions",
+        &body,
+        llm_config.timeout_secs,
+    )
+    .await?;
+    let mut reply = output_llama_response(chat).ok_or(LlmError::EmptyOutput)?;
+    if let Content::Text(text) = &reply.content {
+        // the schema yields a bare JSON call, without the <tool_call> tags
+        if let Some(tool_call) = tool_call_from_json(text) {
+            reply.content = Content::from_calls(vec![tool_call]);
+        }
+    }
+    Ok(reply)
+}
+
+/// Posts `body` to `path` on the endpoint of `config` and decodes the reply,
+/// giving up after `timeout_secs` when set.
+///
+/// async-openai can't decode llama.cpp's error bodies, whose `code` is the
+/// HTTP status as a number, so requests go out through `http` and failures
+/// are classified from their status and body with `LlmError::from_response`.
+async fn post_json<T: DeserializeOwned>(
+    http: &reqwest::Client,
+    config: &LocalServiceProviderConfig,
+    path: &str,
+    body: &impl Serialize,
+    timeout_secs: Option<u64>,
+) -> Result<T, LlmError> {
+    let mut builder = post_request(http, config, path, body)?;
+    if let Some(secs) = timeout_secs {
+        builder = builder.timeout(Duration::from_secs(secs));
+    }
+
+    let res = builder
+        .send()
+        .await
+        .map_err(|e| LlmError::from_reqwest(e, timeout_secs))?;
+    let status = res.status();
+    let text = res
+        .text()
+        .await
+        .map_err(|e| LlmError::from_reqwest(e, timeout_secs))?;
+    if !status.is_success() {
+        return Err(LlmError::from_response(status.as_u16(), &text));
+    }
+    serde_json::from_str(&text).map_err(|e| LlmError::Parse(e.to_string()))
+}
+
+fn post_request(
+    http: &reqwest::Client,
+    config: &LocalServiceProviderConfig,
+    path: &str,
+    body: &impl Serialize,
+) -> Result<RequestBuilder, LlmError> {
+    let body = serde_json::to_string(body).map_err(|e| LlmError::Parse(e.to_string()))?;
+    Ok(http
+        .post(config.url(path))
+        .query(&config.query())
+        .headers(config.headers())
+        .bearer_auth(config.api_key().expose_secret())
+        .body(body))
+}
+
+/// Reads the error body when the server refused a streamed request.
+async fn event_source_error(error: EventSourceError) -> LlmError {
+    match error {
+        EventSourceError::InvalidStatusCode(status, res) => {
+            LlmError::from_response(status.as_u16(), &res.text().await.unwrap_or_default())
+        }
+        EventSourceError::Transport(e) => LlmError::from(e),
+        other => LlmError::Parse(other.to_string()),
+    }
+}
+
+/// `LlamaLocalClient::complete` on the shared client.
+pub async fn chat_inner_async_completion(
+    messages: Vec<Message>,
+    llm_config: &LlmConfig,
+) -> Result<LlamaResponseMessage, LlmError> {
+    LlamaLocalClient::shared()
+        .complete(messages, llm_config)
+        .await
+}
+
+/// The parts of a request that decide the reply, used to match recorded and
+/// cached responses.
+pub fn request_fingerprint(messages: &[Message]) -> Value {
+    Value::Array(
+        messages
+            .iter()
+            .map(|m| json!({ "role": m.role, "name": m.name, "content": m.content }))
+            .collect(),
+    )
+}
+
+/// Calls `client.chat`, retrying transient failures with the backoff from
+/// `llm_config.retry`. Permanent errors are returned right away.
+///
+/// Each attempt waits for a slot under `llm_config.concurrency`, the slot is
+/// given back while backing off.
+pub async fn chat_with_retry(
+    client: &dyn LlmClient,
+    messages: Vec<Message>,
+    llm_config: &LlmConfig,
+) -> Result<LlamaResponseMessage, LlmError> {
+    let mut attempt = 0;
+    loop {
+        let permit = acquire_permit(llm_config).await;
+        let result = client.chat(messages.clone(), llm_config).await;
+        drop(permit);
+        match result {
+            Ok(reply) => return Ok(reply),
+            Err(e) if e.is_transient() && attempt < llm_config.retry.max_retries => {
+                let delay = llm_config.retry.backoff(attempt);
+                log::warn!(
+                    "LLM call failed ({}), retry {}/{} in {:?}",
+                    e,
+                    attempt + 1,
+                    llm_config.retry.max_retries,
+                    delay
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
         }
     }
 }
 
+/// `chat_with_cache`, asking the model to go on while the reply is cut off at
+/// `max_tokens`, up to `llm_config.max_continuations` times. The continued text
+/// is appended to the reply and the usage of every request added up.
+///
+/// Each continuation resends the history with the reply so far, fitted to the
+/// context again. Once the reply alone no longer fits, it is returned cut off.
+pub async fn chat_with_continuation(
+    client: &dyn LlmClient,
+    messages: Vec<Message>,
+    llm_config: &LlmConfig,
+) -> Result<LlamaResponseMessage, LlmError> {
+    let mut reply = chat_with_cache(client, messages.clone(), llm_config).await?;
+    for _ in 0..llm_config.max_continuations {
+        let text = match &reply.content {
+            Content::Text(text) if reply.is_truncated() => text.clone(),
+            _ => break,
+        };
+        let mut request = messages.clone();
+        request.push(Message::new(
+            Some(Content::Text(text.clone())),
+            None,
+            Some(Role::Assistant),
+            None,
+        ));
+        request.push(Message::new(
+            Some(Content::Text(
+                "Continue exactly where your last reply stopped, without repeating any of it."
+                    .to_string(),
+            )),
+            None,
+            Some(Role::User),
+            None,
+        ));
+        let (request, report) = llm_config.fit_to_context(request);
+        if llm_config
+            .prompt_budget()
+            .is_some_and(|budget| report.kept_tokens > budget)
+        {
+            log::warn!(
+                "{}: the reply is too long to continue within the context window",
+                llm_config.model
+            );
+            break;
+        }
+
+        let next = chat_with_cache(client, request, llm_config).await?;
+        let Content::Text(more) = next.content else {
+            break;
+        };
+        reply.content = Content::Text(text + &more);
+        reply.usage.prompt_tokens += next.usage.prompt_tokens;
+        reply.usage.completion_tokens += next.usage.completion_tokens;
+        reply.usage.total_tokens += next.usage.total_tokens;
+        reply.finish_reason = next.finish_reason;
+        reply.cached &= next.cached;
+        if let (Some(logprobs), Some(more)) = (reply.logprobs.as_mut(), next.logprobs) {
+            logprobs.extend(more);
+        }
+    }
+    Ok(reply)
+}
+
+/// Streaming counterpart of `chat_inner_async_llama`.
+///
+/// llama.cpp does not report usage on streamed replies, so the final usage is
+/// estimated: one token per delta, and `estimate_message_tokens` for the prompt.
+pub async fn chat_inner_async_llama_stream(
+    messages: Vec<Message>,
+    llm_config: &LlmConfig,
+) -> Result<LlmStream, LlmError> {
+    LlamaLocalClient::shared()
+        .chat_stream(messages, llm_config)
+        .await
+}
+
 pub fn parse_summary_from_raw_json(input: &str) -> String {
     #[derive(Deserialize, Debug)]
     struct SummaryStruct {
@@ -238,7 +1136,7 @@ pub fn parse_summary_from_raw_json(input: &str) -> String {
         .filter(|field| !field.is_empty()) // Filter out empty strings
         .fold(String::new(), |mut acc, field| {
             if !acc.is_empty() {
-                acc.push_str(" ");
+                acc.push(' ');
             }
             acc.push_str(field);
             acc
@@ -251,13 +1149,527 @@ pub fn parse_issue_summary_from_json(input: &str) -> anyhow::Result<Vec<(String,
     let summaries = parsed
         .iter()
         .filter_map(|(key, value)| {
-            if let Some(summary_str) = value.as_str() {
-                Some((key.clone(), summary_str.to_owned()))
-            } else {
-                None
-            }
+            value
+                .as_str()
+                .map(|summary_str| (key.clone(), summary_str.to_owned()))
         })
         .collect::<Vec<(String, String)>>(); // Collect into a Vec of tuples
 
     Ok(summaries)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm_config::ApiKeySource;
+    use crate::test_server::{completion, stream_chunk, user_message, MockResponse, MockServer};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn local_config(server: &MockServer) -> LlmConfig {
+        LlmConfig {
+            api_base: format!("{}/v1", server.url),
+            api_key: ApiKeySource::None,
+            ..Default::default()
+        }
+    }
+
+    /// Fails with `error` until `failures` calls have been made.
+    struct FlakyClient {
+        failures: usize,
+        error: LlmError,
+        calls: Mutex<usize>,
+    }
+
+    #[async_trait]
+    impl LlmClient for FlakyClient {
+        async fn chat(
+            &self,
+            _messages: Vec<Message>,
+            _llm_config: &LlmConfig,
+        ) -> Result<LlamaResponseMessage, LlmError> {
+            let mut calls = self.calls.lock().unwrap();
+            *calls += 1;
+            if *calls <= self.failures {
+                return Err(self.error.clone());
+            }
+            Ok(LlamaResponseMessage::new(
+                Content::Text("ok".to_string()),
+                Role::Assistant,
+                CompletionUsage {
+                    prompt_tokens: 1,
+                    completion_tokens: 1,
+                    total_tokens: 2,
+                },
+            ))
+        }
+    }
+
+    fn no_backoff(max_retries: u32) -> LlmConfig {
+        let mut llm_config = LlmConfig::default();
+        llm_config.retry.max_retries = max_retries;
+        llm_config.retry.initial_backoff_ms = 0;
+        llm_config
+    }
+
+    #[tokio::test]
+    async fn transient_errors_are_retried_up_to_max_retries() {
+        let client = FlakyClient {
+            failures: 2,
+            error: LlmError::Server("Loading model".to_string()),
+            calls: Mutex::new(0),
+        };
+        let reply = chat_with_retry(&client, vec![user_message("hi")], &no_backoff(2)).await;
+        assert!(reply.is_ok());
+        assert_eq!(*client.calls.lock().unwrap(), 3);
+
+        let client = FlakyClient {
+            failures: 3,
+            error: LlmError::Server("Loading model".to_string()),
+            calls: Mutex::new(0),
+        };
+        let reply = chat_with_retry(&client, vec![user_message("hi")], &no_backoff(2)).await;
+        assert!(matches!(reply, Err(LlmError::Server(_))));
+        assert_eq!(*client.calls.lock().unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn permanent_errors_are_not_retried() {
+        let client = FlakyClient {
+            failures: 1,
+            error: LlmError::ContextLength("too long".to_string()),
+            calls: Mutex::new(0),
+        };
+        let reply = chat_with_retry(&client, vec![user_message("hi")], &no_backoff(3)).await;
+        assert!(matches!(reply, Err(LlmError::ContextLength(_))));
+        assert_eq!(*client.calls.lock().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn llama_cpp_errors_are_classified_from_the_body() {
+        let calls = AtomicUsize::new(0);
+        let server = MockServer::start(move |request| {
+            if request.body["messages"].as_array().unwrap().len() > 1 {
+                return MockResponse::json(
+                    400,
+                    json!({ "error": {
+                        "code": 400,
+                        "message": "the request exceeds the available context size",
+                        "type": "exceed_context_size_error",
+                    }}),
+                );
+            }
+            match calls.fetch_add(1, Ordering::SeqCst) {
+                0 => MockResponse::json(
+                    503,
+                    json!({ "error": {
+                        "code": 503, "message": "Loading model", "type": "unavailable_error",
+                    }}),
+                ),
+                _ => MockResponse::json(200, completion("ready")),
+            }
+        })
+        .await;
+        let mut llm_config = local_config(&server);
+        llm_config.retry = no_backoff(1).retry;
+        let client = LlamaLocalClient::new();
+
+        let reply = chat_with_retry(&client, vec![user_message("hi")], &llm_config)
+            .await
+            .unwrap();
+        assert_eq!(reply.content, Content::Text("ready".to_string()));
+        assert_eq!(server.requests().len(), 2);
+
+        let overflow = client
+            .chat(vec![user_message("hi"), user_message("again")], &llm_config)
+            .await;
+        assert!(matches!(overflow, Err(LlmError::ContextLength(_))));
+    }
+
+    #[tokio::test]
+    async fn streamed_tool_call_deltas_are_assembled() {
+        let server = MockServer::start(|_| {
+            MockResponse::sse(&[
+                stream_chunk(
+                    json!({ "role": "assistant", "tool_calls": [{
+                        "index": 0, "id": "call_1", "type": "function",
+                        "function": { "name": "get_weather", "arguments": "" },
+                    }]}),
+                    None,
+                ),
+                stream_chunk(
+                    json!({ "tool_calls": [{ "index": 0, "function": { "arguments": "{\"city\": " } }] }),
+                    None,
+                ),
+                stream_chunk(
+                    json!({ "tool_calls": [{ "index": 0, "function": { "arguments": "\"Glasgow\"}" } }] }),
+                    Some("tool_calls"),
+                ),
+            ])
+        })
+        .await;
+
+        let stream = LlamaLocalClient::new()
+            .chat_stream(vec![user_message("weather?")], &local_config(&server))
+            .await
+            .unwrap();
+        let chunks: Vec<StreamChunk> = stream.map(Result::unwrap).collect().await;
+
+        let Some(StreamChunk::Done(reply)) = chunks.last() else {
+            panic!("stream did not end with Done: {:?}", chunks);
+        };
+        let calls = reply.content.tool_calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].id, "call_1");
+        assert_eq!(calls[0].name, "get_weather");
+        assert_eq!(calls[0].str_argument("city"), Some("Glasgow"));
+        assert_eq!(reply.finish_reason, Some(FinishReason::ToolCalls));
+
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/v1/chat/completions");
+        assert_eq!(requests[0].body["stream"], json!(true));
+    }
+
+    fn weather_tool() -> ToolDefinition {
+        ToolDefinition {
+            name: "get_weather".to_string(),
+            description: Some("Current weather in a city".to_string()),
+            parameters: json!({
+                "type": "object",
+                "properties": { "city": { "type": "string" } },
+                "required": ["city"],
+            }),
+        }
+    }
+
+    fn constrained_config(server: &MockServer) -> LlmConfig {
+        LlmConfig {
+            tools: vec![weather_tool()],
+            constrain_tool_calls: true,
+            ..local_config(server)
+        }
+    }
+
+    #[tokio::test]
+    async fn constrained_calls_list_the_tools_in_the_system_prompt() {
+        let server = MockServer::start(|_| {
+            MockResponse::json(
+                200,
+                completion(r#"{"name": "get_weather", "arguments": {"city": "Glasgow"}}"#),
+            )
+        })
+        .await;
+
+        let reply = LlamaLocalClient::new()
+            .chat(vec![user_message("weather?")], &constrained_config(&server))
+            .await
+            .unwrap();
+        let calls = reply.content.tool_calls();
+        assert_eq!(calls[0].name, "get_weather");
+        assert_eq!(calls[0].str_argument("city"), Some("Glasgow"));
+
+        let body = &server.requests()[0].body;
+        assert!(body["tools"].is_null());
+        assert!(body["json_schema"]["oneOf"].is_array());
+        assert_eq!(body["messages"][0]["role"], "system");
+        let system = body["messages"][0]["content"].as_str().unwrap();
+        assert!(system.contains("<tools>"));
+        assert!(system.contains("get_weather"));
+        assert_eq!(body["messages"][1]["content"], "weather?");
+    }
+
+    #[tokio::test]
+    async fn embeddings_are_batched_and_ordered_by_index() {
+        let server = MockServer::start(|request| {
+            let inputs = request.body["input"].as_array().unwrap();
+            // servers may answer out of order, `index` says which input is which
+            let data: Vec<Value> = inputs
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(i, input)| {
+                    json!({
+                        "object": "embedding",
+                        "index": i,
+                        "embedding": [input.as_str().unwrap().len() as f32],
+                    })
+                })
+                .collect();
+            MockResponse::json(
+                200,
+                json!({
+                    "object": "list",
+                    "model": "nomic-embed-text",
+                    "data": data,
+                    "usage": { "prompt_tokens": 3, "total_tokens": 3 },
+                }),
+            )
+        })
+        .await;
+        let mut llm_config = local_config(&server);
+        llm_config.embedding.model = Some("nomic-embed-text".to_string());
+        llm_config.embedding.batch_size = 2;
+
+        let inputs = vec!["a".to_string(), "bb".to_string(), "ccc".to_string()];
+        let vectors = LlamaLocalClient::new()
+            .embed(inputs, &llm_config)
+            .await
+            .unwrap();
+        assert_eq!(vectors, [vec![1.0], vec![2.0], vec![3.0]]);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].path, "/v1/embeddings");
+        assert_eq!(requests[0].body["model"], "nomic-embed-text");
+        assert_eq!(requests[0].body["input"], json!(["a", "bb"]));
+        assert_eq!(requests[1].body["input"], json!(["ccc"]));
+    }
+
+    #[tokio::test]
+    async fn images_are_sent_as_content_parts() {
+        let server = MockServer::start(|_| MockResponse::json(200, completion("a cat"))).await;
+        let question = Message::new(
+            Some(Content::Parts(vec![
+                ContentPart::text("What is this?"),
+                ContentPart::image_url("data:image/png;base64,iVBORw0KGgo="),
+            ])),
+            None,
+            Some(Role::User),
+            None,
+        );
+
+        LlamaLocalClient::new()
+            .chat(vec![question], &local_config(&server))
+            .await
+            .unwrap();
+
+        let body = &server.requests()[0].body;
+        assert_eq!(
+            body["messages"][0]["content"],
+            json!([
+                { "type": "text", "text": "What is this?" },
+                { "type": "image_url", "image_url": { "detail": "auto", "url": "data:image/png;base64,iVBORw0KGgo=" } },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn constrained_calls_are_honored_when_streaming() {
+        let server = MockServer::start(|_| {
+            MockResponse::json(
+                200,
+                completion(r#"{"name": "get_weather", "arguments": {"city": "Oslo"}}"#),
+            )
+        })
+        .await;
+
+        let stream = LlamaLocalClient::new()
+            .chat_stream(vec![user_message("weather?")], &constrained_config(&server))
+            .await
+            .unwrap();
+        let chunks: Vec<StreamChunk> = stream.map(Result::unwrap).collect().await;
+        let Some(StreamChunk::Done(reply)) = chunks.last() else {
+            panic!("stream did not end with Done: {:?}", chunks);
+        };
+        assert_eq!(
+            reply.content.tool_calls()[0].str_argument("city"),
+            Some("Oslo")
+        );
+        assert!(server.requests()[0].body["json_schema"].is_object());
+    }
+
+    #[test]
+    fn tools_prompt_joins_an_existing_system_message() {
+        let system = Message::new(
+            Some(Content::Text("You are helpful.".to_string())),
+            None,
+            Some(Role::System),
+            None,
+        );
+        let messages = with_tools_prompt(vec![system, user_message("hi")], &[weather_tool()]);
+        assert_eq!(messages.len(), 2);
+        let text = messages[0].content_to_string().unwrap();
+        assert!(text.starts_with("You are helpful.\n\n"));
+        assert!(text.contains("get_weather"));
+
+        // a prompt that already lists the tools is kept as written
+        let again = with_tools_prompt(messages.clone(), &[weather_tool()]);
+        assert_eq!(
+            again[0].content_to_string(),
+            messages[0].content_to_string()
+        );
+    }
+
+    #[test]
+    fn pooled_clients_do_not_expose_the_api_key() {
+        let client = LlamaLocalClient::new();
+        for key in ["sk-first-secret", "sk-second-secret"] {
+            let llm_config = LlmConfig {
+                api_key: ApiKeySource::Value(key.to_string()),
+                ..Default::default()
+            };
+            client.openai_client(&llm_config).unwrap();
+            client.openai_client(&llm_config).unwrap();
+        }
+
+        let clients = client.clients.lock().unwrap();
+        assert_eq!(clients.len(), 2);
+        assert!(clients.keys().all(|key| !key.contains("secret")));
+        drop(clients);
+        assert!(!format!("{:?}", client).contains("secret"));
+    }
+
+    #[test]
+    fn keys_from_the_environment_are_read_once_per_client() {
+        let var = "AUTOGEN_RUST_TEST_POOLED_KEY";
+        let llm_config = LlmConfig {
+            api_key: ApiKeySource::Env(var.to_string()),
+            ..Default::default()
+        };
+        let client = LlamaLocalClient::new();
+
+        assert!(matches!(
+            client.openai_client(&llm_config),
+            Err(LlmError::Config(_))
+        ));
+        std::env::set_var(var, "sk-first");
+        let first = client.openai_client(&llm_config).unwrap();
+        std::env::set_var(var, "sk-second");
+        let second = client.openai_client(&llm_config).unwrap();
+
+        assert_eq!(first.config().api_key().expose_secret(), "sk-first");
+        assert_eq!(second.config().api_key().expose_secret(), "sk-first");
+        assert_eq!(client.clients.lock().unwrap().len(), 1);
+    }
+
+    /// Answers with `replies` in order, each cut off at `max_tokens` but the last.
+    struct CutOffClient {
+        replies: Mutex<Vec<String>>,
+        requests: Mutex<Vec<Vec<Message>>>,
+    }
+
+    impl CutOffClient {
+        fn new(replies: &[&str]) -> Self {
+            CutOffClient {
+                replies: Mutex::new(replies.iter().rev().map(|r| r.to_string()).collect()),
+                requests: Mutex::new(Vec::new()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl LlmClient for CutOffClient {
+        async fn chat(
+            &self,
+            messages: Vec<Message>,
+            _llm_config: &LlmConfig,
+        ) -> Result<LlamaResponseMessage, LlmError> {
+            self.requests.lock().unwrap().push(messages);
+            let mut replies = self.replies.lock().unwrap();
+            let text = replies.pop().ok_or(LlmError::EmptyOutput)?;
+            let mut reply = LlamaResponseMessage::new(
+                Content::Text(text),
+                Role::Assistant,
+                CompletionUsage {
+                    prompt_tokens: 10,
+                    completion_tokens: 5,
+                    total_tokens: 15,
+                },
+            );
+            reply.finish_reason = Some(if replies.is_empty() {
+                FinishReason::Stop
+            } else {
+                FinishReason::Length
+            });
+            Ok(reply)
+        }
+    }
+
+    #[tokio::test]
+    async fn continuations_resend_the_reply_so_far_once() {
+        let client = CutOffClient::new(&["part one, ", "part two, ", "the end"]);
+        let llm_config = LlmConfig {
+            max_continuations: 2,
+            ..Default::default()
+        };
+
+        let reply = chat_with_continuation(&client, vec![user_message("tell me")], &llm_config)
+            .await
+            .unwrap();
+        assert_eq!(
+            reply.content,
+            Content::Text("part one, part two, the end".to_string())
+        );
+        assert_eq!(reply.usage.total_tokens, 45);
+
+        let requests = client.requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[2].len(), 3);
+        assert_eq!(
+            requests[2][1].content,
+            Some(Content::Text("part one, part two, ".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn continuations_are_fitted_to_the_context() {
+        let long_turn = "word ".repeat(100);
+        let history: Vec<Message> = (0..4).map(|_| user_message(&long_turn)).collect();
+        let mut llm_config = LlmConfig {
+            max_tokens: 100,
+            max_continuations: 3,
+            ..Default::default()
+        };
+        llm_config.context.context_limit = Some(300);
+
+        // the history is trimmed to make room for the reply so far
+        let client = CutOffClient::new(&["short start, ", "done"]);
+        chat_with_continuation(&client, history.clone(), &llm_config)
+            .await
+            .unwrap();
+        let requests = client.requests.lock().unwrap().clone();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].len() < history.len() + 2);
+        let sent: usize = requests[1].iter().map(estimate_message_tokens).sum();
+        assert!(sent <= 200);
+
+        // a reply that fills the budget by itself is returned as it is
+        let client = CutOffClient::new(&[&"word ".repeat(200), "unused"]);
+        let reply = chat_with_continuation(&client, history, &llm_config)
+            .await
+            .unwrap();
+        assert!(reply.is_truncated());
+        assert_eq!(client.requests.lock().unwrap().len(), 1);
+    }
+
+    fn assistant_message(content: Content) -> Message {
+        Message::new(Some(content), None, Some(Role::Assistant), None)
+    }
+
+    #[test]
+    fn text_calls_are_resent_as_the_text_they_came_from() {
+        let written = "Let me check.\n<tool_call>\n{\"name\": \"get_weather\", \"arguments\": {\"city\": \"Glasgow\"}}\n</tool_call>";
+        let message = assistant_message(parse_llama_content(written));
+
+        let sent = json!(ChatCompletionRequestMessage::from(message));
+        assert!(sent["tool_calls"].is_null(), "{}", sent);
+        assert_eq!(sent["content"], written);
+    }
+
+    #[test]
+    fn native_calls_are_resent_as_tool_calls() {
+        let native = ToolCall::from(ChatCompletionMessageToolCall {
+            id: "call_7".to_string(),
+            r#type: ChatCompletionToolType::Function,
+            function: FunctionCall {
+                name: "get_weather".to_string(),
+                arguments: r#"{"city": "Oslo"}"#.to_string(),
+            },
+        });
+        let message = assistant_message(Content::from_calls(vec![native]));
+
+        let sent = json!(ChatCompletionRequestMessage::from(message));
+        assert!(sent["content"].is_null(), "{}", sent);
+        assert_eq!(sent["tool_calls"][0]["id"], "call_7");
+        assert_eq!(sent["tool_calls"][0]["function"]["name"], "get_weather");
+    }
+}
@@ -0,0 +1,634 @@
+use crate::conversable_agent::Message;
+use crate::llama_structs::{
+    generate_call_id, parse_llama_content, tool_output_text, Content, ContentPart,
+    LlamaResponseMessage, ToolCall,
+};
+use crate::llm_config::LlmConfig;
+use crate::llm_error::LlmError;
+use crate::llm_llama_local::{LlmClient, LlmStream, StreamChunk};
+use async_openai::types::{ChatCompletionTool, CompletionUsage, FinishReason, Role};
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::time::Duration;
+
+/// Ollama-only settings, the `ollama` section of `LlmConfig`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+#[serde(default)]
+pub struct OllamaOptions {
+    /// Context window to load the model with.
+    pub num_ctx: Option<u32>,
+    /// How long the model stays loaded after the request, e.g. "5m" or "-1".
+    pub keep_alive: Option<String>,
+    /// Set to "json" to force a JSON reply.
+    pub format: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct OllamaChatRequest {
+    model: String,
+    messages: Vec<OllamaMessage>,
+    stream: bool,
+    options: Map<String, Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<ChatCompletionTool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OllamaMessage {
+    role: String,
+    #[serde(default)]
+    content: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<OllamaToolCall>,
+    /// Base64 images, without the `data:` prefix.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    images: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OllamaToolCall {
+    function: OllamaFunction,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OllamaFunction {
+    name: String,
+    #[serde(default)]
+    arguments: Map<String, Value>,
+}
+
+/// One reply object, or one line of a streamed reply.
+#[derive(Debug, Deserialize)]
+struct OllamaChatResponse {
+    message: Option<OllamaMessage>,
+    #[serde(default)]
+    done: bool,
+    #[serde(default)]
+    prompt_eval_count: u32,
+    #[serde(default)]
+    eval_count: u32,
+    done_reason: Option<String>,
+    error: Option<String>,
+}
+
+impl From<&Message> for OllamaMessage {
+    fn from(message: &Message) -> OllamaMessage {
+        let role = match message.role {
+            Some(Role::System) => "system",
+            Some(Role::User) => "user",
+            Some(Role::Tool) | Some(Role::Function) => "tool",
+            Some(Role::Assistant) | None => "assistant",
+        };
+        match &message.content {
+            // calls read from the reply text go back as that text
+            Some(Content::ToolCalls { text, calls }) => OllamaMessage {
+                role: role.to_string(),
+                content: text
+                    .iter()
+                    .chain(calls.iter().filter_map(|call| call.source_text.as_ref()))
+                    .cloned()
+                    .collect::<Vec<String>>()
+                    .join("\n"),
+                tool_calls: calls
+                    .iter()
+                    .filter(|call| call.source_text.is_none())
+                    .map(|tool_call| OllamaToolCall {
+                        function: OllamaFunction {
+                            name: tool_call.name.clone(),
+                            arguments: tool_call.arguments.clone().unwrap_or_default(),
+                        },
+                    })
+                    .collect(),
+                images: Vec::new(),
+            },
+            // Ollama matches results to calls by position, the id is not sent
+            Some(Content::ToolResult {
+                output, is_error, ..
+            }) => OllamaMessage {
+                role: "tool".to_string(),
+                content: tool_output_text(output, *is_error),
+                tool_calls: Vec::new(),
+                images: Vec::new(),
+            },
+            // Ollama only takes inline images, URLs are passed on as text
+            Some(Content::Parts(parts)) => OllamaMessage {
+                role: role.to_string(),
+                content: parts
+                    .iter()
+                    .filter_map(|part| match part {
+                        ContentPart::Text(text) => Some(text.clone()),
+                        ContentPart::ImageUrl(url) if !url.starts_with("data:") => {
+                            Some(url.clone())
+                        }
+                        ContentPart::ImageUrl(_) => None,
+                    })
+                    .collect::<Vec<String>>()
+                    .join("\n"),
+                tool_calls: Vec::new(),
+                images: parts
+                    .iter()
+                    .filter_map(|part| part.inline_image_data().map(|data| data.to_string()))
+                    .collect(),
+            },
+            _ => OllamaMessage {
+                role: role.to_string(),
+                content: message.content_to_string().unwrap_or_default(),
+                tool_calls: Vec::new(),
+                images: Vec::new(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct OllamaEmbedRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaEmbedResponse {
+    #[serde(default)]
+    embeddings: Vec<Vec<f32>>,
+    error: Option<String>,
+}
+
+fn usage_of(res: &OllamaChatResponse) -> CompletionUsage {
+    CompletionUsage {
+        prompt_tokens: res.prompt_eval_count,
+        completion_tokens: res.eval_count,
+        total_tokens: res.prompt_eval_count + res.eval_count,
+    }
+}
+
+/// Client for Ollama's native `/api/chat` and `/api/embed`, with `api_base` like `http://127.0.0.1:11434`.
+#[derive(Clone, Debug, Default)]
+pub struct OllamaClient {
+    http: Client,
+}
+
+impl OllamaClient {
+    pub fn new() -> Self {
+        OllamaClient {
+            http: Client::new(),
+        }
+    }
+
+    fn build_request(
+        &self,
+        messages: &[Message],
+        llm_config: &LlmConfig,
+        stream: bool,
+    ) -> OllamaChatRequest {
+        let mut options = Map::new();
+        options.insert("num_predict".to_string(), llm_config.max_tokens.into());
+        if let Some(temperature) = llm_config.temperature {
+            options.insert("temperature".to_string(), temperature.into());
+        }
+        if let Some(top_p) = llm_config.top_p {
+            options.insert("top_p".to_string(), top_p.into());
+        }
+        if !llm_config.stop.is_empty() {
+            options.insert("stop".to_string(), llm_config.stop.clone().into());
+        }
+        if let Some(seed) = llm_config.seed {
+            options.insert("seed".to_string(), seed.into());
+        }
+        if let Some(num_ctx) = llm_config.ollama.num_ctx {
+            options.insert("num_ctx".to_string(), num_ctx.into());
+        }
+
+        OllamaChatRequest {
+            model: llm_config.model.clone(),
+            messages: messages.iter().map(OllamaMessage::from).collect(),
+            stream,
+            options,
+            format: llm_config
+                .ollama
+                .format
+                .clone()
+                .or_else(|| llm_config.json_mode.then(|| "json".to_string())),
+            keep_alive: llm_config.ollama.keep_alive.clone(),
+            tools: llm_config
+                .tools
+                .iter()
+                .map(ChatCompletionTool::from)
+                .collect(),
+        }
+    }
+
+    async fn send<T: Serialize>(
+        &self,
+        path: &str,
+        request: &T,
+        llm_config: &LlmConfig,
+    ) -> Result<Response, LlmError> {
+        let body = serde_json::to_string(request).map_err(|e| LlmError::Parse(e.to_string()))?;
+        let mut builder = self
+            .http
+            .post(format!("{}{}", llm_config.api_base, path))
+            .header(CONTENT_TYPE, "application/json")
+            .body(body);
+        if let Some(secs) = llm_config.timeout_secs {
+            builder = builder.timeout(Duration::from_secs(secs));
+        }
+
+        let res = builder
+            .send()
+            .await
+            .map_err(|e| LlmError::from_reqwest(e, llm_config.timeout_secs))?;
+        let status = res.status();
+        if status.is_success() {
+            return Ok(res);
+        }
+
+        let text = res.text().await.unwrap_or_default();
+        let message = serde_json::from_str::<OllamaChatResponse>(&text)
+            .ok()
+            .and_then(|r| r.error)
+            .unwrap_or(text);
+        Err(LlmError::from_status(status.as_u16(), message))
+    }
+}
+
+fn finish_reason_of(res: &OllamaChatResponse) -> Option<FinishReason> {
+    match res.done_reason.as_deref()? {
+        "length" => Some(FinishReason::Length),
+        _ => Some(FinishReason::Stop),
+    }
+}
+
+fn into_llama_response(
+    message: OllamaMessage,
+    usage: CompletionUsage,
+    finish_reason: Option<FinishReason>,
+) -> Result<LlamaResponseMessage, LlmError> {
+    let content = if !message.tool_calls.is_empty() {
+        // Ollama does not number its calls
+        Content::ToolCalls {
+            text: Some(message.content).filter(|text| !text.trim().is_empty()),
+            calls: message
+                .tool_calls
+                .into_iter()
+                .map(|call| ToolCall {
+                    id: generate_call_id(),
+                    name: call.function.name,
+                    arguments: Some(call.function.arguments),
+                    raw_arguments: None,
+                    source_text: None,
+                })
+                .collect(),
+        }
+    } else if message.content.is_empty() {
+        return Err(LlmError::EmptyOutput);
+    } else {
+        parse_llama_content(&message.content)
+    };
+    let mut response = LlamaResponseMessage::new(content, Role::Assistant, usage);
+    response.finish_reason = finish_reason;
+    Ok(response)
+}
+
+#[async_trait]
+impl LlmClient for OllamaClient {
+    async fn chat(
+        &self,
+        messages: Vec<Message>,
+        llm_config: &LlmConfig,
+    ) -> Result<LlamaResponseMessage, LlmError> {
+        let request = self.build_request(&messages, llm_config, false);
+        let res = self.send("/api/chat", &request, llm_config).await?;
+        let text = res
+            .text()
+            .await
+            .map_err(|e| LlmError::from_reqwest(e, llm_config.timeout_secs))?;
+        let reply: OllamaChatResponse =
+            serde_json::from_str(&text).map_err(|e| LlmError::Parse(e.to_string()))?;
+        if let Some(error) = reply.error {
+            return Err(LlmError::Api(error));
+        }
+
+        let usage = usage_of(&reply);
+        let finish_reason = finish_reason_of(&reply);
+        let message = reply.message.ok_or(LlmError::EmptyOutput)?;
+        into_llama_response(message, usage, finish_reason)
+    }
+
+    async fn chat_stream(
+        &self,
+        messages: Vec<Message>,
+        llm_config: &LlmConfig,
+    ) -> Result<LlmStream, LlmError> {
+        let request = self.build_request(&messages, llm_config, true);
+        let mut res = self.send("/api/chat", &request, llm_config).await?;
+        let timeout_secs = llm_config.timeout_secs;
+
+        let (tx, rx) = mpsc::unbounded();
+        tokio::spawn(async move {
+            // replies arrive as newline-delimited JSON objects
+            let mut buffer: Vec<u8> = Vec::new();
+            let mut text = String::new();
+            let mut tool_calls = Vec::new();
+
+            loop {
+                let chunk = match res.chunk().await {
+                    Ok(Some(chunk)) => chunk,
+                    Ok(None) => break,
+                    Err(e) => {
+                        let _ = tx.unbounded_send(Err(LlmError::from_reqwest(e, timeout_secs)));
+                        return;
+                    }
+                };
+                buffer.extend_from_slice(&chunk);
+
+                while let Some(pos) = buffer.iter().position(|&b| b == b'\n') {
+                    let line: Vec<u8> = buffer.drain(..=pos).collect();
+                    if line.iter().all(|b| b.is_ascii_whitespace()) {
+                        continue;
+                    }
+                    let part: OllamaChatResponse = match serde_json::from_slice(&line) {
+                        Ok(part) => part,
+                        Err(e) => {
+                            let _ = tx.unbounded_send(Err(LlmError::Parse(e.to_string())));
+                            return;
+                        }
+                    };
+                    if let Some(error) = part.error {
+                        let _ = tx.unbounded_send(Err(LlmError::Api(error)));
+                        return;
+                    }
+                    let usage = usage_of(&part);
+                    let finish_reason = finish_reason_of(&part);
+                    if let Some(message) = part.message {
+                        tool_calls.extend(message.tool_calls);
+                        if !message.content.is_empty() {
+                            text.push_str(&message.content);
+                            if tx
+                                .unbounded_send(Ok(StreamChunk::Delta(message.content)))
+                                .is_err()
+                            {
+                                // receiver dropped, stop reading
+                                return;
+                            }
+                        }
+                    }
+                    if part.done {
+                        let message = OllamaMessage {
+                            role: "assistant".to_string(),
+                            content: std::mem::take(&mut text),
+                            tool_calls: std::mem::take(&mut tool_calls),
+                            images: Vec::new(),
+                        };
+                        let _ = tx.unbounded_send(
+                            into_llama_response(message, usage, finish_reason)
+                                .map(StreamChunk::Done),
+                        );
+                        return;
+                    }
+                }
+            }
+
+            let _ = tx.unbounded_send(Err(LlmError::Transport(
+                "Ollama stream ended before done".to_string(),
+            )));
+        });
+
+        Ok(Box::pin(rx))
+    }
+
+    async fn embed(
+        &self,
+        inputs: Vec<String>,
+        llm_config: &LlmConfig,
+    ) -> Result<Vec<Vec<f32>>, LlmError> {
+        let mut vectors = Vec::with_capacity(inputs.len());
+        for batch in inputs.chunks(llm_config.embedding.batch_size.max(1)) {
+            let request = OllamaEmbedRequest {
+                model: llm_config.embedding_model(),
+                input: batch,
+                keep_alive: llm_config.ollama.keep_alive.clone(),
+            };
+            let res = self.send("/api/embed", &request, llm_config).await?;
+            let text = res
+                .text()
+                .await
+                .map_err(|e| LlmError::from_reqwest(e, llm_config.timeout_secs))?;
+            let reply: OllamaEmbedResponse =
+                serde_json::from_str(&text).map_err(|e| LlmError::Parse(e.to_string()))?;
+            if let Some(error) = reply.error {
+                return Err(LlmError::Api(error));
+            }
+            if reply.embeddings.len() != batch.len() {
+                return Err(LlmError::Parse(format!(
+                    "expected {} embeddings, got {}",
+                    batch.len(),
+                    reply.embeddings.len()
+                )));
+            }
+            vectors.extend(reply.embeddings);
+        }
+        Ok(vectors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{user_message, MockResponse, MockServer};
+    use futures::StreamExt;
+    use serde_json::json;
+
+    fn ollama_config(server: &MockServer) -> LlmConfig {
+        LlmConfig {
+            api_base: server.url.clone(),
+            model: "llama3".to_string(),
+            temperature: Some(0.0),
+            ollama: OllamaOptions {
+                num_ctx: Some(8192),
+                keep_alive: Some("5m".to_string()),
+                format: None,
+            },
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn chat_reads_the_reply_and_usage() {
+        let server = MockServer::start(|_| {
+            MockResponse::json(
+                200,
+                json!({
+                    "model": "llama3",
+                    "message": {"role": "assistant", "content": "Hello there"},
+                    "done": true,
+                    "done_reason": "stop",
+                    "prompt_eval_count": 12,
+                    "eval_count": 3
+                }),
+            )
+        })
+        .await;
+
+        let reply = OllamaClient::new()
+            .chat(vec![user_message("hi")], &ollama_config(&server))
+            .await
+            .unwrap();
+        assert_eq!(reply.content, Content::Text("Hello there".to_string()));
+        assert_eq!(reply.usage.total_tokens, 15);
+        assert_eq!(reply.finish_reason, Some(FinishReason::Stop));
+
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/api/chat");
+        let body = &requests[0].body;
+        assert_eq!(body["stream"], false);
+        assert_eq!(body["keep_alive"], "5m");
+        assert_eq!(body["options"]["num_ctx"], 8192);
+        assert_eq!(body["options"]["temperature"], 0.0);
+        assert_eq!(
+            body["messages"][0],
+            json!({"role": "user", "content": "hi"})
+        );
+    }
+
+    #[test]
+    fn inline_images_go_in_the_images_field() {
+        let message = Message::new(
+            Some(Content::Parts(vec![
+                ContentPart::text("Compare these"),
+                ContentPart::image_url("data:image/png;base64,iVBORw0KGgo="),
+                ContentPart::image_url("https://example.com/cat.png"),
+            ])),
+            None,
+            Some(Role::User),
+            None,
+        );
+
+        let ollama = OllamaMessage::from(&message);
+        assert_eq!(ollama.role, "user");
+        assert_eq!(ollama.content, "Compare these\nhttps://example.com/cat.png");
+        assert_eq!(ollama.images, ["iVBORw0KGgo="]);
+    }
+
+    #[tokio::test]
+    async fn chat_reads_native_tool_calls() {
+        let server = MockServer::start(|_| {
+            MockResponse::json(
+                200,
+                json!({
+                    "message": {
+                        "role": "assistant",
+                        "content": "",
+                        "tool_calls": [{"function": {"name": "get_time", "arguments": {"zone": "UTC"}}}]
+                    },
+                    "done": true
+                }),
+            )
+        })
+        .await;
+
+        let reply = OllamaClient::new()
+            .chat(vec![user_message("time?")], &ollama_config(&server))
+            .await
+            .unwrap();
+        match reply.content {
+            Content::ToolCalls { text, calls } => {
+                assert_eq!(text, None);
+                assert_eq!(calls[0].name, "get_time");
+                assert_eq!(calls[0].str_argument("zone"), Some("UTC"));
+            }
+            other => panic!("expected tool calls, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_reads_ndjson_lines() {
+        let server = MockServer::start(|_| {
+            MockResponse::ndjson(&[
+                json!({"message": {"role": "assistant", "content": "Hel"}, "done": false}),
+                json!({"message": {"role": "assistant", "content": "lo"}, "done": false}),
+                json!({
+                    "message": {"role": "assistant", "content": ""},
+                    "done": true,
+                    "done_reason": "length",
+                    "prompt_eval_count": 7,
+                    "eval_count": 2
+                }),
+            ])
+        })
+        .await;
+
+        let chunks: Vec<StreamChunk> = OllamaClient::new()
+            .chat_stream(vec![user_message("hi")], &ollama_config(&server))
+            .await
+            .unwrap()
+            .map(|chunk| chunk.unwrap())
+            .collect()
+            .await;
+
+        let deltas: Vec<&str> = chunks
+            .iter()
+            .filter_map(|chunk| match chunk {
+                StreamChunk::Delta(text) => Some(text.as_str()),
+                StreamChunk::Done(_) => None,
+            })
+            .collect();
+        assert_eq!(deltas, ["Hel", "lo"]);
+        match chunks.last() {
+            Some(StreamChunk::Done(reply)) => {
+                assert_eq!(reply.content, Content::Text("Hello".to_string()));
+                assert_eq!(reply.usage.total_tokens, 9);
+                assert_eq!(reply.finish_reason, Some(FinishReason::Length));
+            }
+            other => panic!("expected the final reply, got {:?}", other),
+        }
+        assert_eq!(server.requests()[0].body["stream"], true);
+    }
+
+    #[tokio::test]
+    async fn embed_sends_inputs_in_batches() {
+        let server = MockServer::start(|request| {
+            let count = request.body["input"].as_array().map_or(0, |a| a.len());
+            let embeddings: Vec<Value> = (0..count).map(|i| json!([i as f32, 1.0])).collect();
+            MockResponse::json(200, json!({ "embeddings": embeddings }))
+        })
+        .await;
+        let mut config = ollama_config(&server);
+        config.embedding.model = Some("nomic-embed-text".to_string());
+        config.embedding.batch_size = 2;
+
+        let inputs: Vec<String> = ["a", "b", "c"].iter().map(|s| s.to_string()).collect();
+        let vectors = OllamaClient::new().embed(inputs, &config).await.unwrap();
+        assert_eq!(vectors.len(), 3);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].path, "/api/embed");
+        assert_eq!(requests[0].body["model"], "nomic-embed-text");
+        assert_eq!(requests[0].body["input"], json!(["a", "b"]));
+        assert_eq!(requests[1].body["input"], json!(["c"]));
+    }
+
+    #[tokio::test]
+    async fn errors_are_classified_by_status() {
+        let server = MockServer::start(|_| {
+            MockResponse::json(404, json!({"error": "model \"llama3\" not found"}))
+        })
+        .await;
+
+        let err = OllamaClient::new()
+            .chat(vec![user_message("hi")], &ollama_config(&server))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, LlmError::Refused(_)), "{:?}", err);
+    }
+}
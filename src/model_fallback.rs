@@ -0,0 +1,335 @@
+use crate::context_budget::TruncationReport;
+use crate::conversable_agent::Message;
+use crate::llama_structs::LlamaResponseMessage;
+use crate::llm_config::{ApiKeySource, LlmConfig};
+use crate::llm_error::LlmError;
+use crate::llm_llama_local::{chat_with_continuation, LlmClient, LlmStream};
+use crate::request_limiter::acquire_permit;
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+
+/// One entry of `LlmConfig.fallbacks`. Unset fields keep the primary's value.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+#[serde(default)]
+pub struct ModelEndpoint {
+    pub api_base: Option<String>,
+    pub model: Option<String>,
+    pub api_key: Option<ApiKeySource>,
+    pub context_limit: Option<usize>,
+}
+
+/// An endpoint that was tried and skipped before the reply was produced.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FallbackRecord {
+    pub api_base: String,
+    pub model: String,
+    pub error: String,
+}
+
+/// A reply together with the endpoint that produced it.
+#[derive(Debug, Clone)]
+pub struct FallbackReply<T = LlamaResponseMessage> {
+    pub reply: T,
+    pub llm_config: LlmConfig,
+    /// Endpoints that failed first, in the order they were tried.
+    pub fallbacks: Vec<FallbackRecord>,
+    pub truncation: TruncationReport,
+}
+
+impl LlmConfig {
+    /// This config pointed at `endpoint`, without further fallbacks.
+    pub fn for_endpoint(&self, endpoint: &ModelEndpoint) -> LlmConfig {
+        let mut llm_config = self.clone();
+        if let Some(api_base) = &endpoint.api_base {
+            llm_config.api_base = api_base.clone();
+        }
+        if let Some(model) = &endpoint.model {
+            llm_config.model = model.clone();
+        }
+        if let Some(api_key) = &endpoint.api_key {
+            llm_config.api_key = api_key.clone();
+        }
+        if endpoint.context_limit.is_some() {
+            llm_config.context.context_limit = endpoint.context_limit;
+        }
+        llm_config.fallbacks = Vec::new();
+        llm_config
+    }
+
+    /// The primary endpoint followed by each fallback, in order.
+    pub fn endpoint_chain(&self) -> Vec<LlmConfig> {
+        let mut chain = vec![self.for_endpoint(&ModelEndpoint::default())];
+        chain.extend(self.fallbacks.iter().map(|e| self.for_endpoint(e)));
+        chain
+    }
+}
+
+impl FallbackRecord {
+    pub fn new(llm_config: &LlmConfig, error: &LlmError) -> Self {
+        FallbackRecord {
+            api_base: llm_config.api_base.clone(),
+            model: llm_config.model.clone(),
+            error: error.to_string(),
+        }
+    }
+}
+
+/// Runs `attempt` against each endpoint of `llm_config.endpoint_chain()` until
+/// one succeeds, trimming the history again for every endpoint. Fails with the
+/// last endpoint's error, or the first error that no endpoint would get past.
+async fn with_fallbacks<T, F, Fut>(
+    messages: Vec<Message>,
+    llm_config: &LlmConfig,
+    mut attempt: F,
+) -> Result<FallbackReply<T>, LlmError>
+where
+    F: FnMut(Vec<Message>, LlmConfig) -> Fut,
+    Fut: Future<Output = Result<T, LlmError>>,
+{
+    let mut fallbacks = Vec::new();
+    let mut last_error = None;
+    let mut chain = llm_config.endpoint_chain().into_iter().peekable();
+    while let Some(llm_config) = chain.next() {
+        let (trimmed, truncation) = llm_config.fit_to_context(messages.clone());
+        match attempt(trimmed, llm_config.clone()).await {
+            Ok(reply) => {
+                return Ok(FallbackReply {
+                    reply,
+                    llm_config,
+                    fallbacks,
+                    truncation,
+                })
+            }
+            Err(e) if e.should_fall_back() => {
+                if chain.peek().is_some() {
+                    log::warn!(
+                        "{} at {} failed ({}), trying the next fallback",
+                        llm_config.model,
+                        llm_config.api_base,
+                        e
+                    );
+                    fallbacks.push(FallbackRecord::new(&llm_config, &e));
+                }
+                last_error = Some(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| LlmError::Config("no endpoint configured".to_string())))
+}
+
+/// Sends `messages` to each endpoint of `llm_config.endpoint_chain()` until one
+/// replies. An endpoint is skipped when it refuses the request, is unavailable
+/// or the prompt exceeds its context.
+pub async fn chat_with_fallbacks(
+    client: &dyn LlmClient,
+    messages: Vec<Message>,
+    llm_config: &LlmConfig,
+) -> Result<FallbackReply, LlmError> {
+    with_fallbacks(messages, llm_config, |messages, llm_config| async move {
+        chat_with_continuation(client, messages, &llm_config).await
+    })
+    .await
+}
+
+/// Streaming counterpart of `chat_with_fallbacks`. An endpoint is skipped when
+/// opening the stream or reading its first chunk fails; an error after that
+/// ends the stream.
+///
+/// The stream holds a slot under `llm_config.concurrency` until it is dropped.
+pub async fn chat_stream_with_fallbacks(
+    client: &dyn LlmClient,
+    messages: Vec<Message>,
+    llm_config: &LlmConfig,
+) -> Result<FallbackReply<LlmStream>, LlmError> {
+    with_fallbacks(messages, llm_config, |messages, llm_config| async move {
+        let permit = acquire_permit(&llm_config).await;
+        let mut stream = client.chat_stream(messages, &llm_config).await?;
+        // some servers accept the request and report the failure as the first chunk
+        let first = match stream.next().await {
+            Some(chunk) => chunk?,
+            None => return Err(LlmError::EmptyOutput),
+        };
+        let stream: LlmStream = Box::pin(stream::once(async { Ok(first) }).chain(stream).map(
+            move |chunk| {
+                let _ = &permit;
+                chunk
+            },
+        ));
+        Ok(stream)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversable_agent::{ConversableAgent, ReplyChunk};
+    use crate::llama_structs::Content;
+    use crate::llm_llama_local::{reply_as_stream, LlamaLocalClient, StreamChunk};
+    use crate::test_server::{streamed_reply, user_message, MockResponse, MockServer};
+    use async_openai::types::{CompletionUsage, Role};
+    use async_trait::async_trait;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn context_overflow() -> MockResponse {
+        MockResponse::json(
+            400,
+            json!({ "error": {
+                "code": 400,
+                "message": "the request exceeds the available context size, try increasing it",
+                "type": "exceed_context_size_error",
+            }}),
+        )
+    }
+
+    /// `primary` with one fallback to `secondary`, both keyless.
+    fn two_endpoints(primary: &MockServer, secondary: &MockServer) -> LlmConfig {
+        LlmConfig {
+            api_base: format!("{}/v1", primary.url),
+            model: "small-context".to_string(),
+            api_key: ApiKeySource::None,
+            fallbacks: vec![ModelEndpoint {
+                api_base: Some(format!("{}/v1", secondary.url)),
+                model: Some("large-context".to_string()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn streamed_replies_record_the_endpoints_skipped() {
+        let primary = MockServer::start(|_| context_overflow()).await;
+        let secondary = MockServer::start(|_| streamed_reply("from the fallback")).await;
+        let mut agent = ConversableAgent::new("assistant");
+        agent.set_llm_client(Arc::new(LlamaLocalClient::new()));
+        agent.llm_config = Some(two_endpoints(&primary, &secondary).to_value());
+
+        let stream = agent
+            .a_generate_reply_stream(vec![user_message("hello")], None)
+            .await
+            .unwrap();
+        let chunks: Vec<ReplyChunk> = stream.map(Result::unwrap).collect().await;
+
+        let Some(ReplyChunk::Done(reply)) = chunks.last() else {
+            panic!("stream did not end with Done");
+        };
+        assert_eq!(
+            reply.content,
+            Some(Content::Text("from the fallback".to_string()))
+        );
+        let context = reply.context.as_ref().unwrap();
+        assert_eq!(context["model"], "large-context");
+        let fallbacks: Vec<FallbackRecord> = serde_json::from_str(&context["fallbacks"]).unwrap();
+        assert_eq!(fallbacks.len(), 1);
+        assert_eq!(fallbacks[0].model, "small-context");
+        assert_eq!(fallbacks[0].api_base, format!("{}/v1", primary.url));
+        assert_eq!(primary.requests().len(), 1);
+        assert_eq!(secondary.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rejected_requests_do_not_fall_back() {
+        let primary = MockServer::start(|_| {
+            MockResponse::json(
+                401,
+                json!({ "error": { "code": 401, "message": "Invalid API Key", "type": "authentication_error" }}),
+            )
+        })
+        .await;
+        let secondary = MockServer::start(|_| streamed_reply("unused")).await;
+
+        let result = chat_with_fallbacks(
+            &LlamaLocalClient::new(),
+            vec![user_message("hello")],
+            &two_endpoints(&primary, &secondary),
+        )
+        .await;
+        assert!(matches!(result, Err(LlmError::Api(_))), "{:?}", result);
+        assert!(secondary.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn the_last_error_is_returned_when_every_endpoint_fails() {
+        let primary = MockServer::start(|_| context_overflow()).await;
+        let secondary = MockServer::start(|_| context_overflow()).await;
+
+        let result = chat_with_fallbacks(
+            &LlamaLocalClient::new(),
+            vec![user_message("hello")],
+            &two_endpoints(&primary, &secondary),
+        )
+        .await;
+        assert!(
+            matches!(result, Err(LlmError::ContextLength(_))),
+            "{:?}",
+            result
+        );
+        assert_eq!(secondary.requests().len(), 1);
+    }
+
+    /// Accepts every streamed request, but `failing_model` only ever streams an error.
+    struct FailsOnFirstChunk {
+        failing_model: String,
+    }
+
+    #[async_trait]
+    impl LlmClient for FailsOnFirstChunk {
+        async fn chat(
+            &self,
+            _messages: Vec<Message>,
+            _llm_config: &LlmConfig,
+        ) -> Result<LlamaResponseMessage, LlmError> {
+            unimplemented!("only streamed in these tests")
+        }
+
+        async fn chat_stream(
+            &self,
+            _messages: Vec<Message>,
+            llm_config: &LlmConfig,
+        ) -> Result<LlmStream, LlmError> {
+            if llm_config.model == self.failing_model {
+                let error = LlmError::Server("Loading model".to_string());
+                return Ok(Box::pin(stream::iter(vec![Err(error)])));
+            }
+            Ok(reply_as_stream(LlamaResponseMessage::new(
+                Content::Text(format!("from {}", llm_config.model)),
+                Role::Assistant,
+                CompletionUsage {
+                    prompt_tokens: 1,
+                    completion_tokens: 1,
+                    total_tokens: 2,
+                },
+            )))
+        }
+    }
+
+    #[tokio::test]
+    async fn errors_in_the_first_streamed_chunk_fall_back() {
+        let llm_config = LlmConfig {
+            model: "primary".to_string(),
+            api_key: ApiKeySource::None,
+            fallbacks: vec![ModelEndpoint {
+                model: Some("secondary".to_string()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let client = FailsOnFirstChunk {
+            failing_model: "primary".to_string(),
+        };
+
+        let streamed =
+            chat_stream_with_fallbacks(&client, vec![user_message("hello")], &llm_config)
+                .await
+                .unwrap();
+        assert_eq!(streamed.llm_config.model, "secondary");
+        assert_eq!(streamed.fallbacks.len(), 1);
+        let chunks: Vec<StreamChunk> = streamed.reply.map(Result::unwrap).collect().await;
+        assert!(matches!(&chunks[0], StreamChunk::Delta(text) if text == "from secondary"));
+        assert!(matches!(chunks.last(), Some(StreamChunk::Done(_))));
+    }
+}
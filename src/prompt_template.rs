@@ -0,0 +1,226 @@
+use crate::conversable_agent::Message;
+use async_openai::types::Role;
+use serde::{Deserialize, Serialize};
+
+const ALPACA_PREAMBLE: &str = "Below is an instruction that describes a task. Write a response that appropriately completes the request.";
+
+/// Chat formats for servers that take a raw prompt instead of a message list.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PromptTemplate {
+    /// `<|im_start|>role ... <|im_end|>`, used by Hermes and Qwen.
+    #[default]
+    ChatMl,
+    Llama3,
+    MistralInstruct,
+    Alpaca,
+}
+
+fn role_name(role: Option<Role>) -> &'static str {
+    match role {
+        Some(Role::System) => "system",
+        Some(Role::User) => "user",
+        Some(Role::Tool) | Some(Role::Function) => "tool",
+        Some(Role::Assistant) | None => "assistant",
+    }
+}
+
+fn text_of(message: &Message) -> String {
+    message.content_to_string().unwrap_or_default()
+}
+
+impl PromptTemplate {
+    /// Renders `messages` into a prompt that ends where the assistant reply starts.
+    pub fn render(&self, messages: &[Message]) -> String {
+        match self {
+            PromptTemplate::ChatMl => render_chatml(messages),
+            PromptTemplate::Llama3 => render_llama3(messages),
+            PromptTemplate::MistralInstruct => render_mistral(messages),
+            PromptTemplate::Alpaca => render_alpaca(messages),
+        }
+    }
+
+    /// Markers that end an assistant turn in this format.
+    pub fn stop_sequences(&self) -> Vec<String> {
+        let stops: &[&str] = match self {
+            PromptTemplate::ChatMl => &["<|im_end|>"],
+            PromptTemplate::Llama3 => &["<|eot_id|>"],
+            PromptTemplate::MistralInstruct => &["</s>", "[/INST]"],
+            PromptTemplate::Alpaca => &["### Instruction:"],
+        };
+        stops.iter().map(|s| s.to_string()).collect()
+    }
+}
+
+fn render_chatml(messages: &[Message]) -> String {
+    let mut prompt = String::new();
+    for message in messages {
+        prompt.push_str(&format!(
+            "<|im_start|>{}\n{}<|im_end|>\n",
+            role_name(message.role),
+            text_of(message)
+        ));
+    }
+    prompt.push_str("<|im_start|>assistant\n");
+    prompt
+}
+
+fn render_llama3(messages: &[Message]) -> String {
+    let mut prompt = String::from("<|begin_of_text|>");
+    for message in messages {
+        prompt.push_str(&format!(
+            "<|start_header_id|>{}<|end_header_id|>\n\n{}<|eot_id|>",
+            role_name(message.role),
+            text_of(message)
+        ));
+    }
+    prompt.push_str("<|start_header_id|>assistant<|end_header_id|>\n\n");
+    prompt
+}
+
+/// Mistral has no system role, system text is folded into the next user turn.
+fn render_mistral(messages: &[Message]) -> String {
+    let mut prompt = String::from("<s>");
+    let mut pending_system = String::new();
+    for message in messages {
+        match message.role {
+            Some(Role::System) => {
+                pending_system.push_str(&text_of(message));
+                pending_system.push_str("\n\n");
+            }
+            Some(Role::Assistant) | None => {
+                prompt.push_str(&format!(" {}</s>", text_of(message)));
+            }
+            _ => {
+                prompt.push_str(&format!(
+                    "[INST] {}{} [/INST]",
+                    pending_system,
+                    text_of(message)
+                ));
+                pending_system.clear();
+            }
+        }
+    }
+    if !pending_system.is_empty() {
+        prompt.push_str(&format!("[INST] {} [/INST]", pending_system.trim_end()));
+    }
+    prompt
+}
+
+fn render_alpaca(messages: &[Message]) -> String {
+    let system: Vec<String> = messages
+        .iter()
+        .filter(|m| m.role == Some(Role::System))
+        .map(text_of)
+        .collect();
+    let mut prompt = if system.is_empty() {
+        ALPACA_PREAMBLE.to_string()
+    } else {
+        system.join("\n")
+    };
+    prompt.push_str("\n\n");
+
+    for message in messages {
+        match message.role {
+            Some(Role::System) => {}
+            Some(Role::Assistant) | None => {
+                prompt.push_str(&format!("### Response:\n{}\n\n", text_of(message)));
+            }
+            _ => {
+                prompt.push_str(&format!("### Instruction:\n{}\n\n", text_of(message)));
+            }
+        }
+    }
+    prompt.push_str("### Response:\n");
+    prompt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llama_structs::Content;
+
+    fn message(role: Role, text: &str) -> Message {
+        Message::new(
+            Some(Content::Text(text.to_string())),
+            None,
+            Some(role),
+            None,
+        )
+    }
+
+    fn conversation() -> Vec<Message> {
+        vec![
+            message(Role::System, "Be brief."),
+            message(Role::User, "Hi"),
+            message(Role::Assistant, "Hello!"),
+            message(Role::User, "Bye"),
+        ]
+    }
+
+    #[test]
+    fn chatml_wraps_every_turn() {
+        assert_eq!(
+            PromptTemplate::ChatMl.render(&conversation()),
+            "<|im_start|>system\nBe brief.<|im_end|>\n\
+             <|im_start|>user\nHi<|im_end|>\n\
+             <|im_start|>assistant\nHello!<|im_end|>\n\
+             <|im_start|>user\nBye<|im_end|>\n\
+             <|im_start|>assistant\n"
+        );
+        assert_eq!(PromptTemplate::ChatMl.stop_sequences(), ["<|im_end|>"]);
+    }
+
+    #[test]
+    fn llama3_uses_header_ids() {
+        assert_eq!(
+            PromptTemplate::Llama3.render(&conversation()),
+            "<|begin_of_text|>\
+             <|start_header_id|>system<|end_header_id|>\n\nBe brief.<|eot_id|>\
+             <|start_header_id|>user<|end_header_id|>\n\nHi<|eot_id|>\
+             <|start_header_id|>assistant<|end_header_id|>\n\nHello!<|eot_id|>\
+             <|start_header_id|>user<|end_header_id|>\n\nBye<|eot_id|>\
+             <|start_header_id|>assistant<|end_header_id|>\n\n"
+        );
+        assert_eq!(PromptTemplate::Llama3.stop_sequences(), ["<|eot_id|>"]);
+    }
+
+    #[test]
+    fn mistral_folds_the_system_prompt_into_the_next_instruction() {
+        assert_eq!(
+            PromptTemplate::MistralInstruct.render(&conversation()),
+            "<s>[INST] Be brief.\n\nHi [/INST] Hello!</s>[INST] Bye [/INST]"
+        );
+        assert_eq!(
+            PromptTemplate::MistralInstruct.render(&[message(Role::System, "Be brief.")]),
+            "<s>[INST] Be brief. [/INST]"
+        );
+        assert_eq!(
+            PromptTemplate::MistralInstruct.stop_sequences(),
+            ["</s>", "[/INST]"]
+        );
+    }
+
+    #[test]
+    fn alpaca_puts_the_system_prompt_first() {
+        assert_eq!(
+            PromptTemplate::Alpaca.render(&conversation()),
+            "Be brief.\n\n\
+             ### Instruction:\nHi\n\n\
+             ### Response:\nHello!\n\n\
+             ### Instruction:\nBye\n\n\
+             ### Response:\n"
+        );
+        assert_eq!(
+            PromptTemplate::Alpaca.render(&[message(Role::User, "Hi")]),
+            format!(
+                "{}\n\n### Instruction:\nHi\n\n### Response:\n",
+                ALPACA_PREAMBLE
+            )
+        );
+        assert_eq!(
+            PromptTemplate::Alpaca.stop_sequences(),
+            ["### Instruction:"]
+        );
+    }
+}
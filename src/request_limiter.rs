@@ -0,0 +1,354 @@
+use crate::llm_config::LlmConfig;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+/// `concurrency` section of `LlmConfig`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+#[serde(default)]
+pub struct ConcurrencyConfig {
+    /// Requests in flight to `api_base` at once, unlimited when unset. Set it
+    /// to the server's slot count, e.g. llama.cpp `--parallel`.
+    pub max_concurrent: Option<usize>,
+    /// Queued requests with a higher priority are sent first.
+    pub priority: i32,
+}
+
+/// Queue wait times of one endpoint.
+#[derive(Debug, Clone, Serialize, PartialEq, Default)]
+pub struct QueueStats {
+    pub requests: u64,
+    /// Requests that found every permit taken.
+    pub queued: u64,
+    pub total_wait: Duration,
+    pub max_wait: Duration,
+    pub in_flight: usize,
+    pub waiting: usize,
+}
+
+impl QueueStats {
+    pub fn average_wait(&self) -> Duration {
+        if self.requests == 0 {
+            Duration::ZERO
+        } else {
+            self.total_wait / self.requests as u32
+        }
+    }
+}
+
+struct Waiter {
+    priority: i32,
+    seq: u64,
+    tx: oneshot::Sender<()>,
+}
+
+// max-heap order: higher priority first, then the earlier arrival
+impl Ord for Waiter {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Waiter {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Waiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Waiter {}
+
+struct LimiterState {
+    available: usize,
+    waiting: BinaryHeap<Waiter>,
+    next_seq: u64,
+    stats: QueueStats,
+}
+
+/// Semaphore with a priority queue, shared by every request to one endpoint.
+pub struct EndpointLimiter {
+    max_concurrent: usize,
+    state: Mutex<LimiterState>,
+}
+
+/// Held while a request is in flight, the permit goes to the next waiter on drop.
+pub struct RequestPermit {
+    limiter: Arc<EndpointLimiter>,
+    waited: Duration,
+}
+
+impl RequestPermit {
+    /// Time this request spent in the queue.
+    pub fn waited(&self) -> Duration {
+        self.waited
+    }
+}
+
+impl Drop for RequestPermit {
+    fn drop(&mut self) {
+        self.limiter.release();
+    }
+}
+
+/// Pending place in the queue. If the acquiring future is dropped after the
+/// permit was handed over, the permit is passed on instead of being lost.
+struct QueuedWaiter {
+    limiter: Arc<EndpointLimiter>,
+    rx: Option<oneshot::Receiver<()>>,
+}
+
+impl Drop for QueuedWaiter {
+    fn drop(&mut self) {
+        if let Some(mut rx) = self.rx.take() {
+            rx.close();
+            if rx.try_recv().is_ok() {
+                self.limiter.release();
+            }
+        }
+    }
+}
+
+impl EndpointLimiter {
+    pub fn new(max_concurrent: usize) -> Arc<Self> {
+        let max_concurrent = max_concurrent.max(1);
+        Arc::new(EndpointLimiter {
+            max_concurrent,
+            state: Mutex::new(LimiterState {
+                available: max_concurrent,
+                waiting: BinaryHeap::new(),
+                next_seq: 0,
+                stats: QueueStats::default(),
+            }),
+        })
+    }
+
+    pub fn max_concurrent(&self) -> usize {
+        self.max_concurrent
+    }
+
+    /// Waits for a free slot. Among waiters, higher `priority` goes first and
+    /// equal priorities are served in arrival order.
+    pub async fn acquire(self: &Arc<Self>, priority: i32) -> RequestPermit {
+        let start = Instant::now();
+        let rx = {
+            let mut state = self.state.lock().unwrap();
+            if state.available > 0 && state.waiting.is_empty() {
+                state.available -= 1;
+                None
+            } else {
+                let (tx, rx) = oneshot::channel();
+                let seq = state.next_seq;
+                state.next_seq += 1;
+                state.waiting.push(Waiter { priority, seq, tx });
+                Some(rx)
+            }
+        };
+
+        let was_queued = rx.is_some();
+        if let Some(rx) = rx {
+            let mut queued = QueuedWaiter {
+                limiter: self.clone(),
+                rx: Some(rx),
+            };
+            // the sender is only dropped after a hand-off, never before
+            let _ = queued.rx.as_mut().unwrap().await;
+            queued.rx = None;
+        }
+
+        let waited = start.elapsed();
+        let mut state = self.state.lock().unwrap();
+        let stats = &mut state.stats;
+        stats.requests += 1;
+        stats.total_wait += waited;
+        stats.max_wait = stats.max_wait.max(waited);
+        if was_queued {
+            stats.queued += 1;
+        }
+        drop(state);
+
+        RequestPermit {
+            limiter: self.clone(),
+            waited,
+        }
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        while let Some(waiter) = state.waiting.pop() {
+            // a closed receiver means that request was cancelled, try the next one
+            if waiter.tx.send(()).is_ok() {
+                return;
+            }
+        }
+        state.available += 1;
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        let state = self.state.lock().unwrap();
+        QueueStats {
+            in_flight: self.max_concurrent - state.available,
+            waiting: state.waiting.len(),
+            ..state.stats.clone()
+        }
+    }
+}
+
+fn limiters() -> &'static Mutex<HashMap<String, Arc<EndpointLimiter>>> {
+    static LIMITERS: OnceLock<Mutex<HashMap<String, Arc<EndpointLimiter>>>> = OnceLock::new();
+    LIMITERS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Limiter shared by every request to `api_base` in this process. The first
+/// `max_concurrent` requested for an endpoint sticks, a different one later
+/// is logged and ignored.
+pub fn endpoint_limiter(api_base: &str, max_concurrent: usize) -> Arc<EndpointLimiter> {
+    let limiter = limiters()
+        .lock()
+        .unwrap()
+        .entry(api_base.to_string())
+        .or_insert_with(|| EndpointLimiter::new(max_concurrent))
+        .clone();
+    if limiter.max_concurrent() != max_concurrent.max(1) {
+        log::warn!(
+            "{} is already limited to {} concurrent requests, ignoring max_concurrent = {}",
+            api_base,
+            limiter.max_concurrent(),
+            max_concurrent
+        );
+    }
+    limiter
+}
+
+/// Waits for a slot on `llm_config.api_base`, `None` when it has no limit.
+pub async fn acquire_permit(llm_config: &LlmConfig) -> Option<RequestPermit> {
+    let max_concurrent = llm_config.concurrency.max_concurrent?;
+    let limiter = endpoint_limiter(&llm_config.api_base, max_concurrent);
+    Some(limiter.acquire(llm_config.concurrency.priority).await)
+}
+
+/// Queue stats of `api_base`, if any request to it was limited.
+pub fn queue_stats(api_base: &str) -> Option<QueueStats> {
+    limiters()
+        .lock()
+        .unwrap()
+        .get(api_base)
+        .map(|limiter| limiter.stats())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    /// Waits until `waiting` requests are queued on `limiter`.
+    async fn queued(limiter: &EndpointLimiter, waiting: usize) {
+        while limiter.stats().waiting < waiting {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_beyond_the_limit_wait_for_a_permit() {
+        let limiter = EndpointLimiter::new(2);
+        let first = limiter.acquire(0).await;
+        let _second = limiter.acquire(0).await;
+        assert!(limiter.acquire(0).now_or_never().is_none());
+        assert_eq!(limiter.stats().in_flight, 2);
+
+        drop(first);
+        assert!(limiter.acquire(0).now_or_never().is_some());
+    }
+
+    #[tokio::test]
+    async fn higher_priorities_go_first_then_arrival_order() {
+        let limiter = EndpointLimiter::new(1);
+        let held = limiter.acquire(0).await;
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let mut tasks = Vec::new();
+        for (i, (name, priority)) in [("a", 0), ("b", 5), ("c", 0), ("d", 5)]
+            .into_iter()
+            .enumerate()
+        {
+            let (waiter, order) = (limiter.clone(), order.clone());
+            tasks.push(tokio::spawn(async move {
+                let _permit = waiter.acquire(priority).await;
+                order.lock().unwrap().push(name);
+            }));
+            queued(&limiter, i + 1).await;
+        }
+        drop(held);
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(*order.lock().unwrap(), ["b", "d", "a", "c"]);
+    }
+
+    #[tokio::test]
+    async fn dropped_waiters_pass_their_permit_on() {
+        let limiter = EndpointLimiter::new(1);
+        let held = limiter.acquire(0).await;
+
+        // cancelled while queued
+        let cancelled = tokio::spawn({
+            let limiter = limiter.clone();
+            async move {
+                limiter.acquire(0).await;
+            }
+        });
+        queued(&limiter, 1).await;
+        cancelled.abort();
+        let _ = cancelled.await;
+
+        // handed the permit, but dropped before taking it
+        let mut handed_over = Box::pin(limiter.acquire(0));
+        assert!(futures::poll!(&mut handed_over).is_pending());
+        drop(held);
+        drop(handed_over);
+
+        assert_eq!(limiter.stats().in_flight, 0);
+        assert!(limiter.acquire(0).now_or_never().is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn queue_stats_record_the_wait() {
+        let limiter = EndpointLimiter::new(1);
+        let held = limiter.acquire(0).await;
+        let waiter = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire(0).await.waited() }
+        });
+        queued(&limiter, 1).await;
+        assert_eq!(limiter.stats().waiting, 1);
+
+        tokio::time::advance(Duration::from_secs(2)).await;
+        drop(held);
+        assert_eq!(waiter.await.unwrap(), Duration::from_secs(2));
+
+        let stats = limiter.stats();
+        assert_eq!(stats.requests, 2);
+        assert_eq!(stats.queued, 1);
+        assert_eq!(stats.max_wait, Duration::from_secs(2));
+        assert_eq!(stats.average_wait(), Duration::from_secs(1));
+        assert_eq!((stats.in_flight, stats.waiting), (0, 0));
+    }
+
+    #[test]
+    fn the_first_limit_of_an_endpoint_sticks() {
+        let api_base = "http://127.0.0.1:1/limiter-test";
+        assert_eq!(endpoint_limiter(api_base, 2).max_concurrent(), 2);
+        assert_eq!(endpoint_limiter(api_base, 5).max_concurrent(), 2);
+    }
+}
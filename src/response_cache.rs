@@ -0,0 +1,184 @@
+use crate::conversable_agent::Message;
+use crate::llama_structs::LlamaResponseMessage;
+use crate::llm_config::LlmConfig;
+use crate::llm_error::LlmError;
+use crate::llm_llama_local::{chat_with_retry, request_fingerprint, LlmClient};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+/// `cache` section of `LlmConfig`, off by default.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    pub dir: PathBuf,
+    /// Also cache sampled replies. Without it only requests with
+    /// `temperature: 0` are cached, since llama.cpp samples by default.
+    pub cache_nonzero_temperature: bool,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            enabled: false,
+            dir: PathBuf::from(".llm_cache"),
+            cache_nonzero_temperature: false,
+        }
+    }
+}
+
+impl CacheConfig {
+    pub fn should_cache(&self, temperature: Option<f32>) -> bool {
+        self.enabled && (self.cache_nonzero_temperature || temperature == Some(0.0))
+    }
+}
+
+/// `LlmConfig` sections that decide how a call is made, not what is asked.
+const NOT_REQUEST_SHAPING: &[&str] = &[
+    "api_key",
+    "timeout_secs",
+    "retry",
+    "cache",
+    "context",
+    "concurrency",
+    "fallbacks",
+    "embedding",
+    "max_continuations",
+];
+
+/// Stable key for a request: the messages and every config field that shapes
+/// the request, endpoint and prompt format included. Fields added to
+/// `LlmConfig` are part of the key unless listed in `NOT_REQUEST_SHAPING`.
+pub fn cache_key(messages: &[Message], llm_config: &LlmConfig) -> String {
+    let mut config = llm_config.to_value();
+    if let Some(config) = config.as_object_mut() {
+        for field in NOT_REQUEST_SHAPING {
+            config.remove(*field);
+        }
+    }
+    let request = json!({
+        "config": config,
+        "messages": request_fingerprint(messages),
+    });
+    format!("{:x}", Sha256::digest(request.to_string().as_bytes()))
+}
+
+/// Replies stored as `<dir>/<key>.json`.
+pub struct ResponseCache {
+    dir: PathBuf,
+}
+
+impl ResponseCache {
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        ResponseCache {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+
+    pub fn get(&self, key: &str) -> Option<LlamaResponseMessage> {
+        let data = std::fs::read_to_string(self.path(key)).ok()?;
+        serde_json::from_str(&data).ok()
+    }
+
+    pub fn put(&self, key: &str, response: &LlamaResponseMessage) -> anyhow::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(self.path(key), serde_json::to_string(response)?)?;
+        Ok(())
+    }
+}
+
+/// `chat_with_retry` behind the on-disk cache configured in `llm_config.cache`.
+pub async fn chat_with_cache(
+    client: &dyn LlmClient,
+    messages: Vec<Message>,
+    llm_config: &LlmConfig,
+) -> Result<LlamaResponseMessage, LlmError> {
+    if !llm_config.cache.should_cache(llm_config.temperature) {
+        return chat_with_retry(client, messages, llm_config).await;
+    }
+
+    let cache = ResponseCache::new(&llm_config.cache.dir);
+    let key = cache_key(&messages, llm_config);
+    if let Some(mut hit) = cache.get(&key) {
+        hit.cached = true;
+        return Ok(hit);
+    }
+
+    let reply = chat_with_retry(client, messages, llm_config).await?;
+    if let Err(e) = cache.put(&key, &reply) {
+        log::warn!("Failed to write LLM cache entry {}: {}", key, e);
+    }
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llama_structs::Content;
+    use crate::prompt_template::PromptTemplate;
+    use async_openai::types::Role;
+
+    fn question() -> Vec<Message> {
+        vec![Message::new(
+            Some(Content::Text("hello".to_string())),
+            None,
+            Some(Role::User),
+            None,
+        )]
+    }
+
+    #[test]
+    fn key_covers_the_endpoint_and_request_options() {
+        let base = LlmConfig::default();
+        let key = cache_key(&question(), &base);
+
+        let changed = [
+            LlmConfig {
+                api_base: "http://127.0.0.1:11434".to_string(),
+                ..base.clone()
+            },
+            LlmConfig {
+                json_mode: true,
+                ..base.clone()
+            },
+            LlmConfig {
+                constrain_tool_calls: true,
+                ..base.clone()
+            },
+            LlmConfig {
+                prompt_template: PromptTemplate::Llama3,
+                ..base.clone()
+            },
+            LlmConfig {
+                ollama: crate::llm_ollama::OllamaOptions {
+                    num_ctx: Some(8192),
+                    ..Default::default()
+                },
+                ..base.clone()
+            },
+        ];
+        for config in &changed {
+            assert_ne!(cache_key(&question(), config), key, "{:?}", config);
+        }
+    }
+
+    #[test]
+    fn key_ignores_how_the_call_is_made() {
+        let base = LlmConfig::default();
+        let config = LlmConfig {
+            timeout_secs: Some(30),
+            max_continuations: 2,
+            ..base.clone()
+        };
+        assert_eq!(
+            cache_key(&question(), &config),
+            cache_key(&question(), &base)
+        );
+    }
+}
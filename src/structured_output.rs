@@ -0,0 +1,224 @@
+use crate::conversable_agent::Message;
+use crate::llama_structs::Content;
+use crate::llm_config::LlmConfig;
+use crate::llm_error::LlmError;
+use crate::llm_llama_local::LlmClient;
+use crate::model_fallback::chat_with_fallbacks;
+use async_openai::types::Role;
+use schemars::{schema_for, JsonSchema};
+use serde::de::DeserializeOwned;
+
+/// Cuts the JSON value out of a reply, skipping code fences and any prose around it.
+pub fn extract_json(reply: &str) -> Option<&str> {
+    let start = reply.find(['{', '['])?;
+    let close = if reply[start..].starts_with('{') {
+        '}'
+    } else {
+        ']'
+    };
+    let end = reply.rfind(close)?;
+    (end > start).then(|| &reply[start..=end])
+}
+
+fn text_message(text: String, role: Role) -> Message {
+    Message::new(Some(Content::Text(text)), None, Some(role), None)
+}
+
+/// Asks the model for a `T` and deserializes the reply.
+///
+/// The JSON schema of `T` goes into the system prompt and the request is sent in
+/// JSON mode. When the reply does not deserialize, or is a tool call, the error
+/// is shown to the model and it is asked again, up to `max_attempts` requests
+/// in total. Requests go through `chat_with_fallbacks`, so the history, which
+/// grows by a reply and a correction every attempt, is fitted to the context.
+pub async fn chat_structured<T: DeserializeOwned + JsonSchema>(
+    client: &dyn LlmClient,
+    mut messages: Vec<Message>,
+    llm_config: &LlmConfig,
+    max_attempts: usize,
+) -> Result<T, LlmError> {
+    let schema = serde_json::to_string(&schema_for!(T)).expect("schema is always serializable");
+    let instruction = format!(
+        "Reply only with a JSON value matching this JSON schema, without any other text: {}",
+        schema
+    );
+    let at = messages
+        .iter()
+        .take_while(|m| m.role == Some(Role::System))
+        .count();
+    messages.insert(at, text_message(instruction, Role::System));
+
+    let mut llm_config = llm_config.clone();
+    llm_config.json_mode = true;
+
+    let mut last_error = LlmError::EmptyOutput;
+    for _ in 0..max_attempts.max(1) {
+        let reply = chat_with_fallbacks(client, messages.clone(), &llm_config)
+            .await?
+            .reply;
+        let (text, error) = match reply.content {
+            Content::Text(text) => {
+                let error = match extract_json(&text) {
+                    Some(json) => match serde_json::from_str::<T>(json) {
+                        Ok(value) => return Ok(value),
+                        Err(e) => e.to_string(),
+                    },
+                    None => "no JSON value found".to_string(),
+                };
+                (text, error)
+            }
+            // resent as text, a call without its result would be rejected
+            other => {
+                let text = Message::new(Some(other), None, None, None)
+                    .content_to_string()
+                    .unwrap_or_default();
+                (text, "expected JSON text, got a tool call".to_string())
+            }
+        };
+
+        messages.push(text_message(text, Role::Assistant));
+        messages.push(text_message(
+            format!(
+                "That reply does not match the schema: {}. Reply again with only the corrected JSON.",
+                error
+            ),
+            Role::User,
+        ));
+        last_error = LlmError::Parse(error);
+    }
+
+    Err(last_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llama_structs::{parse_llama_content, LlamaResponseMessage};
+    use crate::llm_config::ApiKeySource;
+    use crate::test_server::user_message;
+    use async_openai::types::CompletionUsage;
+    use async_trait::async_trait;
+    use serde::Deserialize;
+    use std::sync::Mutex;
+
+    #[derive(Debug, Deserialize, JsonSchema, PartialEq)]
+    struct Person {
+        name: String,
+        age: u32,
+    }
+
+    /// Answers with `replies` in order and keeps the requests it saw.
+    struct ScriptedClient {
+        replies: Mutex<Vec<String>>,
+        requests: Mutex<Vec<Vec<Message>>>,
+    }
+
+    impl ScriptedClient {
+        fn new(replies: &[&str]) -> Self {
+            ScriptedClient {
+                replies: Mutex::new(replies.iter().rev().map(|r| r.to_string()).collect()),
+                requests: Mutex::new(Vec::new()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl LlmClient for ScriptedClient {
+        async fn chat(
+            &self,
+            messages: Vec<Message>,
+            _llm_config: &LlmConfig,
+        ) -> Result<LlamaResponseMessage, LlmError> {
+            self.requests.lock().unwrap().push(messages);
+            let reply = self.replies.lock().unwrap().pop().expect("no reply left");
+            Ok(LlamaResponseMessage::new(
+                parse_llama_content(&reply),
+                Role::Assistant,
+                CompletionUsage {
+                    prompt_tokens: 10,
+                    completion_tokens: 5,
+                    total_tokens: 15,
+                },
+            ))
+        }
+    }
+
+    fn keyless() -> LlmConfig {
+        LlmConfig {
+            api_key: ApiKeySource::None,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn json_is_cut_out_of_fences_and_prose() {
+        assert_eq!(extract_json("```json\n{\"a\": 1}\n```"), Some("{\"a\": 1}"));
+        assert_eq!(
+            extract_json("Sure! Here it is: [1, 2] Hope that helps."),
+            Some("[1, 2]")
+        );
+        assert_eq!(
+            extract_json("{\"a\": {\"b\": 2}} and more"),
+            Some("{\"a\": {\"b\": 2}}")
+        );
+        assert_eq!(extract_json("no json here"), None);
+        assert_eq!(extract_json("} backwards {"), None);
+    }
+
+    #[tokio::test]
+    async fn invalid_replies_are_corrected_by_the_model() {
+        let client = ScriptedClient::new(&[
+            r#"{"name": "Ada", "age": "thirty-six"}"#,
+            r#"<tool_call>{"name": "lookup", "arguments": {"name": "Ada"}}</tool_call>"#,
+            r#"```json
+{"name": "Ada", "age": 36}
+```"#,
+        ]);
+
+        let person: Person = chat_structured(
+            &client,
+            vec![user_message("Who wrote the first program?")],
+            &keyless(),
+            3,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            person,
+            Person {
+                name: "Ada".to_string(),
+                age: 36
+            }
+        );
+        let requests = client.requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0].len(), 2);
+        assert_eq!(requests[0][0].role, Some(Role::System));
+        // each failed attempt adds the reply and a correction
+        let last = &requests[2];
+        assert_eq!(last.len(), 6);
+        assert_eq!(last[3].role, Some(Role::User));
+        assert!(last[3]
+            .content_to_string()
+            .unwrap()
+            .contains("invalid type"));
+        assert_eq!(last[4].role, Some(Role::Assistant));
+        assert!(last[4].content_to_string().unwrap().contains("<tool_call>"));
+        assert!(last[5]
+            .content_to_string()
+            .unwrap()
+            .contains("got a tool call"));
+    }
+
+    #[tokio::test]
+    async fn attempts_are_limited() {
+        let client = ScriptedClient::new(&["not json", "still not json"]);
+
+        let result: Result<Person, LlmError> =
+            chat_structured(&client, vec![user_message("Who?")], &keyless(), 2).await;
+
+        assert!(matches!(result, Err(LlmError::Parse(e)) if e == "no JSON value found"));
+        assert_eq!(client.requests.lock().unwrap().len(), 2);
+    }
+}
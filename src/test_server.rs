@@ -0,0 +1,211 @@
+//! Local HTTP server for tests and benches, standing in for llama.cpp, Ollama
+//! and other OpenAI-compatible endpoints. Every request is answered by a
+//! handler and kept, so tests can check what the client sent.
+//!
+//! Built for the crate's own tests, and for benches with the `test-util` feature.
+
+use crate::conversable_agent::Message;
+use crate::llama_structs::{Content, LlamaResponseMessage};
+use crate::llm_config::LlmConfig;
+use crate::llm_error::LlmError;
+use crate::llm_llama_local::LlmClient;
+use async_openai::types::Role;
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+#[derive(Debug, Clone)]
+pub struct MockRequest {
+    pub path: String,
+    /// The JSON body, `Null` when there is none.
+    pub body: Value,
+}
+
+pub struct MockResponse {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl MockResponse {
+    pub fn json(status: u16, body: Value) -> Self {
+        MockResponse {
+            status,
+            content_type: "application/json",
+            body: body.to_string(),
+        }
+    }
+
+    /// Newline-delimited JSON objects, as Ollama streams.
+    pub fn ndjson(lines: &[Value]) -> Self {
+        MockResponse {
+            status: 200,
+            content_type: "application/x-ndjson",
+            body: lines.iter().map(|line| format!("{}\n", line)).collect(),
+        }
+    }
+
+    /// A server-sent event stream ending in `data: [DONE]`, as OpenAI streams.
+    pub fn sse(events: &[Value]) -> Self {
+        let mut body: String = events
+            .iter()
+            .map(|event| format!("data: {}\n\n", event))
+            .collect();
+        body.push_str("data: [DONE]\n\n");
+        MockResponse {
+            status: 200,
+            content_type: "text/event-stream",
+            body,
+        }
+    }
+}
+
+/// A user turn saying `text`.
+pub fn user_message(text: &str) -> Message {
+    Message::new(
+        Some(Content::Text(text.to_string())),
+        None,
+        Some(Role::User),
+        None,
+    )
+}
+
+/// A `chat.completion` body answering with `content`.
+pub fn completion(content: &str) -> Value {
+    json!({
+        "id": "reply",
+        "object": "chat.completion",
+        "created": 0,
+        "model": "mock",
+        "choices": [{
+            "index": 0,
+            "message": { "role": "assistant", "content": content },
+            "finish_reason": "stop",
+        }],
+        "usage": { "prompt_tokens": 20, "completion_tokens": 8, "total_tokens": 28 },
+    })
+}
+
+/// One `chat.completion.chunk` event of a streamed reply.
+pub fn stream_chunk(delta: Value, finish_reason: Option<&str>) -> Value {
+    json!({
+        "id": "chunk",
+        "object": "chat.completion.chunk",
+        "created": 0,
+        "model": "mock",
+        "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+    })
+}
+
+/// `text` streamed in a single delta, then a `stop`.
+pub fn streamed_reply(text: &str) -> MockResponse {
+    MockResponse::sse(&[
+        stream_chunk(json!({ "role": "assistant", "content": text }), None),
+        stream_chunk(json!({}), Some("stop")),
+    ])
+}
+
+/// Never answers, like a server stuck on a long prompt.
+pub struct HangingClient;
+
+#[async_trait]
+impl LlmClient for HangingClient {
+    async fn chat(
+        &self,
+        _messages: Vec<Message>,
+        _llm_config: &LlmConfig,
+    ) -> Result<LlamaResponseMessage, LlmError> {
+        std::future::pending().await
+    }
+}
+
+type Handler = dyn Fn(&MockRequest) -> MockResponse + Send + Sync;
+
+pub struct MockServer {
+    /// `http://127.0.0.1:<port>`, without a trailing slash.
+    pub url: String,
+    requests: Arc<Mutex<Vec<MockRequest>>>,
+}
+
+impl MockServer {
+    pub async fn start(
+        handler: impl Fn(&MockRequest) -> MockResponse + Send + Sync + 'static,
+    ) -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let handler: Arc<Handler> = Arc::new(handler);
+
+        let seen = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(serve(socket, handler.clone(), seen.clone()));
+            }
+        });
+        MockServer { url, requests }
+    }
+
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+async fn serve(
+    mut socket: TcpStream,
+    handler: Arc<Handler>,
+    requests: Arc<Mutex<Vec<MockRequest>>>,
+) {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let header_end = loop {
+            if let Some(pos) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos + 4;
+            }
+            match socket.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+            }
+        };
+        let headers = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+        let path = headers
+            .split_whitespace()
+            .nth(1)
+            .unwrap_or_default()
+            .to_string();
+        let body_len: usize = headers
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case("content-length")
+                    .then(|| value.trim().parse().ok())?
+            })
+            .unwrap_or(0);
+        while buffer.len() < header_end + body_len {
+            match socket.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+            }
+        }
+        let body = serde_json::from_slice(&buffer[header_end..header_end + body_len])
+            .unwrap_or(Value::Null);
+        buffer.drain(..header_end + body_len);
+
+        let request = MockRequest { path, body };
+        let response = handler(&request);
+        requests.lock().unwrap().push(request);
+
+        let reply = format!(
+            "HTTP/1.1 {} MOCK\r\ncontent-type: {}\r\ncontent-length: {}\r\n\r\n{}",
+            response.status,
+            response.content_type,
+            response.body.len(),
+            response.body
+        );
+        if socket.write_all(reply.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
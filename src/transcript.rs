@@ -0,0 +1,413 @@
+use crate::conversable_agent::{ConversableAgent, Message};
+use crate::groupchat::GroupChat;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+/// Schema version written in the header line. Bump it when a change to the
+/// records would make older readers misread a transcript.
+pub const TRANSCRIPT_VERSION: u32 = 1;
+
+/// One line of a transcript file. The first line is always the header.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TranscriptRecord {
+    Header {
+        version: u32,
+        #[serde(default)]
+        conversation: Option<String>,
+    },
+    Message {
+        /// Key of the message store queue the message was kept in.
+        #[serde(default)]
+        queue: Option<String>,
+        message: Box<Message>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct TranscriptEntry {
+    pub queue: Option<String>,
+    pub message: Message,
+}
+
+/// A saved conversation, in the order the messages were created.
+#[derive(Debug, Clone, Default)]
+pub struct Transcript {
+    pub conversation: Option<String>,
+    pub entries: Vec<TranscriptEntry>,
+}
+
+impl Transcript {
+    /// A transcript of one agent's history, kept in order.
+    pub fn from_messages(conversation: Option<String>, messages: Vec<Message>) -> Self {
+        Transcript {
+            conversation,
+            entries: messages
+                .into_iter()
+                .map(|message| TranscriptEntry {
+                    queue: None,
+                    message,
+                })
+                .collect(),
+        }
+    }
+
+    /// A transcript of every queue of `store`, ordered by `created_at` and
+    /// then `seq`. Messages of one queue keep their queue order.
+    pub fn from_store(
+        conversation: Option<String>,
+        store: &HashMap<String, VecDeque<Message>>,
+    ) -> Self {
+        let mut queues: Vec<_> = store.iter().collect();
+        queues.sort_by(|a, b| a.0.cmp(b.0));
+        let mut entries: Vec<TranscriptEntry> = queues
+            .into_iter()
+            .flat_map(|(queue, messages)| {
+                messages.iter().map(move |message| TranscriptEntry {
+                    queue: Some(queue.clone()),
+                    message: message.clone(),
+                })
+            })
+            .collect();
+        // messages read from older transcripts may share both, the stable sort
+        // then keeps them in queue order
+        entries.sort_by_key(|entry| (entry.message.created_at, entry.message.seq));
+        Transcript {
+            conversation,
+            entries,
+        }
+    }
+
+    pub fn messages(&self) -> Vec<Message> {
+        self.entries.iter().map(|e| e.message.clone()).collect()
+    }
+
+    /// Messages sent or received by `agent`. Messages that name neither a
+    /// sender nor a recipient, such as those built with `Message::new`, are
+    /// kept, since they can't be told apart.
+    pub fn history_of(&self, agent: &str) -> Vec<Message> {
+        self.entries
+            .iter()
+            .map(|e| &e.message)
+            .filter(|m| match (m.sender.as_deref(), m.recipient.as_deref()) {
+                (None, None) => true,
+                (sender, recipient) => sender == Some(agent) || recipient == Some(agent),
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Rebuilds a message store. Entries without a queue go to their recipient's.
+    pub fn to_store(&self) -> HashMap<String, VecDeque<Message>> {
+        let mut store: HashMap<String, VecDeque<Message>> = HashMap::new();
+        for entry in &self.entries {
+            let queue = entry
+                .queue
+                .clone()
+                .or_else(|| entry.message.recipient.clone())
+                .unwrap_or_default();
+            store
+                .entry(queue)
+                .or_default()
+                .push_back(entry.message.clone());
+        }
+        store
+    }
+
+    pub fn write(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let mut writer = BufWriter::new(File::create(path.as_ref())?);
+        let header = TranscriptRecord::Header {
+            version: TRANSCRIPT_VERSION,
+            conversation: self.conversation.clone(),
+        };
+        writeln!(writer, "{}", serde_json::to_string(&header)?)?;
+        for entry in &self.entries {
+            let record = TranscriptRecord::Message {
+                queue: entry.queue.clone(),
+                message: Box::new(entry.message.clone()),
+            };
+            writeln!(writer, "{}", serde_json::to_string(&record)?)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Loads a transcript written by `write`. Transcripts from a newer schema
+    /// version are rejected rather than partly read.
+    pub fn read(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let reader = BufReader::new(File::open(path)?);
+        let mut transcript: Option<Transcript> = None;
+        for (n, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: TranscriptRecord = serde_json::from_str(&line).map_err(|e| {
+                anyhow::anyhow!(
+                    "Bad transcript record at {}:{}: {}",
+                    path.display(),
+                    n + 1,
+                    e
+                )
+            })?;
+            match (record, transcript.as_mut()) {
+                (TranscriptRecord::Header { version, .. }, None)
+                    if version > TRANSCRIPT_VERSION =>
+                {
+                    anyhow::bail!(
+                        "{} has transcript version {}, this build reads up to {}",
+                        path.display(),
+                        version,
+                        TRANSCRIPT_VERSION
+                    );
+                }
+                (TranscriptRecord::Header { conversation, .. }, None) => {
+                    transcript = Some(Transcript {
+                        conversation,
+                        entries: Vec::new(),
+                    });
+                }
+                (TranscriptRecord::Message { queue, message }, Some(transcript)) => {
+                    transcript.entries.push(TranscriptEntry {
+                        queue,
+                        message: *message,
+                    });
+                }
+                (TranscriptRecord::Header { .. }, Some(_)) => {
+                    anyhow::bail!("Second header at {}:{}", path.display(), n + 1);
+                }
+                (TranscriptRecord::Message { .. }, None) => {
+                    anyhow::bail!("{} does not start with a transcript header", path.display());
+                }
+            }
+        }
+        transcript.ok_or_else(|| anyhow::anyhow!("{} is empty", path.display()))
+    }
+}
+
+impl ConversableAgent {
+    pub fn transcript(&self) -> Transcript {
+        Transcript::from_messages(
+            self.conversation_id.clone(),
+            self.chat_messages.clone().unwrap_or_default(),
+        )
+    }
+
+    /// Replaces `chat_messages` with the messages this agent sent or received,
+    /// see `Transcript::history_of`.
+    pub fn load_history(&mut self, transcript: &Transcript) {
+        self.chat_messages = Some(transcript.history_of(&self.name));
+    }
+}
+
+impl GroupChat {
+    pub fn transcript(&self) -> Transcript {
+        Transcript::from_store(Some(self.id.clone()), &self.messages_store.lock().unwrap())
+    }
+
+    /// Replaces `messages_store` and takes over the transcript's conversation id,
+    /// so later usage is recorded under the same conversation.
+    pub fn load_transcript(&mut self, transcript: &Transcript) {
+        if let Some(conversation) = &transcript.conversation {
+            self.id = conversation.clone();
+            let agents: Vec<_> = self.agents.values().map(|a| (**a).clone()).collect();
+            for agent in &agents {
+                self.register(agent);
+            }
+        }
+        *self.messages_store.lock().unwrap() = transcript.to_store();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llama_structs::Content;
+    use async_openai::types::Role;
+
+    fn text_message(text: &str, sender: Option<&str>, recipient: Option<&str>) -> Message {
+        Message {
+            sender: sender.map(str::to_string),
+            recipient: recipient.map(str::to_string),
+            ..Message::new(
+                Some(Content::Text(text.to_string())),
+                None,
+                Some(Role::User),
+                None,
+            )
+        }
+    }
+
+    #[test]
+    fn written_transcripts_read_back_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = dir.path().join("first.jsonl");
+        let second = dir.path().join("second.jsonl");
+        let transcript = Transcript::from_messages(
+            Some("chat-1".to_string()),
+            vec![
+                text_message("hello", Some("user"), Some("assistant")),
+                text_message("hi there", Some("assistant"), Some("user")),
+                text_message("no names", None, None),
+            ],
+        );
+
+        transcript.write(&first).unwrap();
+        let read = Transcript::read(&first).unwrap();
+        read.write(&second).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&first).unwrap(),
+            std::fs::read_to_string(&second).unwrap()
+        );
+        assert_eq!(read.conversation.as_deref(), Some("chat-1"));
+        assert_eq!(
+            serde_json::to_value(read.messages()).unwrap(),
+            serde_json::to_value(transcript.messages()).unwrap()
+        );
+    }
+
+    fn store_ids(store: &HashMap<String, VecDeque<Message>>) -> Vec<(String, Vec<String>)> {
+        let mut ids: Vec<_> = store
+            .iter()
+            .map(|(queue, messages)| {
+                (
+                    queue.clone(),
+                    messages.iter().map(|m| m.id.clone()).collect(),
+                )
+            })
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn group_chats_resume_from_a_written_transcript() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("group.jsonl");
+        let mut chat = GroupChat::new();
+        chat.register(&ConversableAgent::new("assistant"));
+        chat.register(&ConversableAgent::new("critic"));
+        {
+            let mut store = chat.messages_store.lock().unwrap();
+            for (queue, text) in [
+                ("assistant", "draft"),
+                ("critic", "too long"),
+                ("assistant", "shorter draft"),
+            ] {
+                store
+                    .entry(queue.to_string())
+                    .or_default()
+                    .push_back(text_message(text, Some(queue), None));
+            }
+        }
+
+        chat.transcript().write(&path).unwrap();
+        let mut resumed = GroupChat::new();
+        resumed.register(&ConversableAgent::new("assistant"));
+        resumed.register(&ConversableAgent::new("critic"));
+        resumed.load_transcript(&Transcript::read(&path).unwrap());
+
+        assert_eq!(resumed.id, chat.id);
+        for agent in resumed.agents.values() {
+            assert_eq!(agent.conversation_id.as_deref(), Some(chat.id.as_str()));
+        }
+        assert_eq!(
+            store_ids(&resumed.messages_store.lock().unwrap()),
+            store_ids(&chat.messages_store.lock().unwrap())
+        );
+        let texts: Vec<String> = resumed
+            .transcript()
+            .messages()
+            .iter()
+            .filter_map(Message::content_to_string)
+            .collect();
+        assert_eq!(texts, ["draft", "too long", "shorter draft"]);
+    }
+
+    #[test]
+    fn messages_created_in_the_same_millisecond_keep_their_order() {
+        let mut first = text_message("first", Some("zed"), None);
+        let mut second = text_message("second", Some("amy"), None);
+        first.created_at = 1_000;
+        second.created_at = 1_000;
+        let store = HashMap::from([
+            ("zed".to_string(), VecDeque::from([first])),
+            ("amy".to_string(), VecDeque::from([second])),
+        ]);
+
+        let transcript = Transcript::from_store(None, &store);
+        let queues: Vec<_> = transcript
+            .entries
+            .iter()
+            .map(|e| e.queue.as_deref().unwrap())
+            .collect();
+        assert_eq!(queues, ["zed", "amy"]);
+    }
+
+    #[test]
+    fn newer_transcripts_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("future.jsonl");
+        std::fs::write(
+            &path,
+            format!(
+                "{{\"type\":\"header\",\"version\":{}}}\n",
+                TRANSCRIPT_VERSION + 1
+            ),
+        )
+        .unwrap();
+
+        let err = Transcript::read(&path).unwrap_err();
+        assert!(err.to_string().contains("transcript version"), "{}", err);
+    }
+
+    #[test]
+    fn records_without_optional_fields_are_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("minimal.jsonl");
+        std::fs::write(
+            &path,
+            concat!(
+                "{\"type\":\"header\",\"version\":1}\n",
+                "{\"type\":\"message\",\"message\":{\"content\":{\"Text\":\"hello\"},\"name\":null,\"role\":\"user\",\"context\":null}}\n",
+            ),
+        )
+        .unwrap();
+
+        let transcript = Transcript::read(&path).unwrap();
+        assert_eq!(transcript.conversation, None);
+        assert_eq!(transcript.entries.len(), 1);
+        let entry = &transcript.entries[0];
+        assert_eq!(entry.queue, None);
+        assert_eq!(entry.message.sender, None);
+        assert_eq!(entry.message.created_at, 0);
+        assert!(!entry.message.id.is_empty());
+    }
+
+    #[test]
+    fn history_keeps_messages_without_sender_or_recipient() {
+        let transcript = Transcript::from_messages(
+            None,
+            vec![
+                text_message("plain", None, None),
+                text_message("to me", Some("user"), Some("assistant")),
+                text_message("between others", Some("user"), Some("critic")),
+            ],
+        );
+
+        let mut agent = ConversableAgent::new("assistant");
+        agent.load_history(&transcript);
+        let texts: Vec<String> = agent
+            .chat_messages
+            .unwrap()
+            .iter()
+            .filter_map(Message::content_to_string)
+            .collect();
+        assert_eq!(texts, ["plain", "to me"]);
+    }
+}
@@ -0,0 +1,90 @@
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+/// Cancellation token and deadline passed down to one turn of a chat.
+///
+/// Deadlines are on the tokio clock, so tests with paused time can run past them.
+#[derive(Debug, Clone, Default)]
+pub struct TurnControl {
+    pub cancel: CancellationToken,
+    pub deadline: Option<Instant>,
+}
+
+/// Why `TurnControl::run` stopped the work it was given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TurnInterrupted {
+    Cancelled,
+    DeadlineExceeded,
+}
+
+impl fmt::Display for TurnInterrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TurnInterrupted::Cancelled => write!(f, "turn cancelled"),
+            TurnInterrupted::DeadlineExceeded => write!(f, "turn deadline exceeded"),
+        }
+    }
+}
+
+impl std::error::Error for TurnInterrupted {}
+
+impl TurnControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A control that expires `timeout` from now.
+    pub fn with_timeout(timeout: Duration) -> Self {
+        TurnControl {
+            cancel: CancellationToken::new(),
+            deadline: Some(Instant::now() + timeout),
+        }
+    }
+
+    /// For a sub-task: cancelled with this one, and never outliving its deadline.
+    /// `timeout` can make the child's deadline earlier.
+    pub fn child(&self, timeout: Option<Duration>) -> Self {
+        let deadline = match (self.deadline, timeout) {
+            (Some(deadline), Some(timeout)) => Some(deadline.min(Instant::now() + timeout)),
+            (None, Some(timeout)) => Some(Instant::now() + timeout),
+            (deadline, None) => deadline,
+        };
+        TurnControl {
+            cancel: self.cancel.child_token(),
+            deadline,
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Polls `fut` until it finishes, the token is cancelled or the deadline
+    /// passes. An interrupted `fut` is dropped, which aborts in-flight requests.
+    pub async fn run<F: Future>(&self, fut: F) -> Result<F::Output, TurnInterrupted> {
+        if self.cancel.is_cancelled() {
+            return Err(TurnInterrupted::Cancelled);
+        }
+        let deadline = async {
+            match self.deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            biased;
+            _ = self.cancel.cancelled() => Err(TurnInterrupted::Cancelled),
+            _ = deadline => Err(TurnInterrupted::DeadlineExceeded),
+            output = fut => Ok(output),
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use async_openai::types::CompletionUsage;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+/// Tokens spent by one LLM call.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct UsageRecord {
+    pub agent: String,
+    pub model: String,
+    pub conversation: Option<String>,
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+}
+
+impl UsageRecord {
+    pub fn new(
+        agent: &str,
+        model: &str,
+        conversation: Option<&str>,
+        usage: &CompletionUsage,
+    ) -> Self {
+        UsageRecord {
+            agent: agent.to_string(),
+            model: model.to_string(),
+            conversation: conversation.map(|c| c.to_string()),
+            prompt_tokens: usage.prompt_tokens,
+            completion_tokens: usage.completion_tokens,
+        }
+    }
+}
+
+/// Price of a model in currency units per 1000 tokens.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+pub struct ModelPrice {
+    pub prompt_per_1k: f64,
+    pub completion_per_1k: f64,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Default)]
+pub struct UsageTotals {
+    pub requests: usize,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    /// Zero for models without a price.
+    pub cost: f64,
+}
+
+impl UsageTotals {
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+
+    fn add(&mut self, other: &UsageTotals) {
+        self.requests += other.requests;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.cost += other.cost;
+    }
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Default)]
+pub struct UsageSummary {
+    pub total: UsageTotals,
+    pub by_agent: BTreeMap<String, UsageTotals>,
+    pub by_model: BTreeMap<String, UsageTotals>,
+    pub by_conversation: BTreeMap<String, UsageTotals>,
+}
+
+impl UsageSummary {
+    pub fn merge(&mut self, other: &UsageSummary) {
+        self.total.add(&other.total);
+        for (into, from) in [
+            (&mut self.by_agent, &other.by_agent),
+            (&mut self.by_model, &other.by_model),
+            (&mut self.by_conversation, &other.by_conversation),
+        ] {
+            for (key, totals) in from {
+                into.entry(key.clone()).or_default().add(totals);
+            }
+        }
+    }
+}
+
+/// Token usage of every LLM call, shared by the agents that hold it.
+#[derive(Debug, Default)]
+pub struct UsageLedger {
+    records: Mutex<Vec<UsageRecord>>,
+    prices: Mutex<HashMap<String, ModelPrice>>,
+}
+
+impl UsageLedger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_price(&self, model: &str, price: ModelPrice) {
+        self.prices.lock().unwrap().insert(model.to_string(), price);
+    }
+
+    pub fn record(&self, record: UsageRecord) {
+        self.records.lock().unwrap().push(record);
+    }
+
+    pub fn records(&self) -> Vec<UsageRecord> {
+        self.records.lock().unwrap().clone()
+    }
+
+    pub fn summary(&self) -> UsageSummary {
+        self.summary_where(|_| true)
+    }
+
+    /// Totals over the records accepted by `filter`, priced with the current table.
+    pub fn summary_where(&self, filter: impl Fn(&UsageRecord) -> bool) -> UsageSummary {
+        let prices = self.prices.lock().unwrap();
+        let mut summary = UsageSummary::default();
+        for record in self.records.lock().unwrap().iter().filter(|r| filter(r)) {
+            let price = prices.get(&record.model).copied().unwrap_or_default();
+            let totals = UsageTotals {
+                requests: 1,
+                prompt_tokens: record.prompt_tokens as u64,
+                completion_tokens: record.completion_tokens as u64,
+                cost: record.prompt_tokens as f64 / 1000.0 * price.prompt_per_1k
+                    + record.completion_tokens as f64 / 1000.0 * price.completion_per_1k,
+            };
+
+            summary.total.add(&totals);
+            summary
+                .by_agent
+                .entry(record.agent.clone())
+                .or_default()
+                .add(&totals);
+            summary
+                .by_model
+                .entry(record.model.clone())
+                .or_default()
+                .add(&totals);
+            if let Some(conversation) = &record.conversation {
+                summary
+                    .by_conversation
+                    .entry(conversation.clone())
+                    .or_default()
+                    .add(&totals);
+            }
+        }
+        summary
+    }
+}
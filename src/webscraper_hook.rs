@@ -1,6 +1,6 @@
 use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE, USER_AGENT};
-use reqwest::{header::AUTHORIZATION, Client};
-use serde::{Deserialize, Serialize};
+use reqwest::Client;
+use serde::Deserialize;
 
 pub async fn get_webpage_text(url: String) -> anyhow::Result<String> {
     let client = Client::builder().build()?;
@@ -11,60 +11,71 @@ pub async fn get_webpage_text(url: String) -> anyhow::Result<String> {
     Ok(res)
 }
 
+// the structs mirror the whole Bing response, only the snippets are read
+#[allow(dead_code)]
 pub async fn search_bing(query: &str) -> anyhow::Result<String> {
     #[derive(Debug, Clone, Deserialize)]
+    #[serde(rename_all = "camelCase")]
     struct QueryContext {
-        originalQuery: String,
+        original_query: String,
     }
 
     #[derive(Debug, Clone, Deserialize)]
+    #[serde(rename_all = "camelCase")]
     struct WebPage {
         id: String,
         name: String,
         url: String,
-        isFamilyFriendly: bool,
-        displayUrl: String,
+        is_family_friendly: bool,
+        display_url: String,
         snippet: String,
-        dateLastCrawled: String,
+        date_last_crawled: String,
         language: String,
-        isNavigational: bool,
+        is_navigational: bool,
     }
 
     #[derive(Debug, Clone, Deserialize)]
+    #[serde(rename_all = "camelCase")]
     struct WebPages {
-        webSearchUrl: String,
-        totalEstimatedMatches: u64,
+        web_search_url: String,
+        total_estimated_matches: u64,
         value: Vec<WebPage>,
     }
 
     #[derive(Debug, Clone, Deserialize)]
+    #[serde(rename_all = "camelCase")]
     struct RankingResponse {
         mainline: Mainline,
     }
 
     #[derive(Debug, Clone, Deserialize)]
+    #[serde(rename_all = "camelCase")]
     struct Mainline {
         items: Vec<Item>,
     }
 
     #[derive(Debug, Clone, Deserialize)]
+    #[serde(rename_all = "camelCase")]
     struct Item {
-        answerType: String,
-        resultIndex: u64,
+        answer_type: String,
+        result_index: u64,
         value: ItemValue,
     }
 
     #[derive(Debug, Clone, Deserialize)]
+    #[serde(rename_all = "camelCase")]
     struct ItemValue {
         id: String,
     }
 
     #[derive(Debug, Clone, Deserialize)]
+    #[serde(rename_all = "camelCase")]
     struct SearchResponse {
+        #[serde(rename = "_type")]
         _type: String,
-        queryContext: QueryContext,
-        webPages: WebPages,
-        rankingResponse: RankingResponse,
+        query_context: QueryContext,
+        web_pages: WebPages,
+        ranking_response: RankingResponse,
     }
 
     let encoded_query = urlencoding::encode(query);
@@ -88,7 +99,7 @@ pub async fn search_bing(query: &str) -> anyhow::Result<String> {
 
     let search_response = serde_json::from_slice::<SearchResponse>(res.as_bytes())?;
     let out = search_response
-        .webPages
+        .web_pages
         .value
         .iter()
         .map(|val| format!("webpage at {} states: {}", val.url, val.snippet))